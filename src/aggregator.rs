@@ -1,23 +1,48 @@
 use colored::Colorize;
+use rand::RngCore;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use dusa_collection_utils::log;
-use dusa_collection_utils::{errors::ErrorArrayItem, log::LogLevel, stringy::Stringy};
+use dusa_collection_utils::{errors::{ErrorArrayItem, Errors}, log::LogLevel, stringy::Stringy, types::PathType};
 use serde::{Deserialize, Serialize};
 use serde_json::Error;
-use simple_comms::network::send_receive::send_message;
+use simple_comms::network::send_receive::{receive_message, send_data, send_message};
 use simple_comms::protocol::flags::Flags;
+use simple_comms::protocol::message::ProtocolMessage;
 use simple_comms::protocol::proto::Proto;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::UnixStream;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
 use std::{
+    collections::{HashMap, VecDeque},
     fmt,
-    fs::{File, OpenOptions},
-    io::{Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
 };
 
-use crate::encryption::{decrypt_text, encrypt_text};
+use crate::encryption::{decrypt_expecting, decrypt_text, encrypt_tagged, encrypt_text, ContentType};
+use crate::identity::IDENTITYPATHSTR;
+use crate::network::SecurityProfile;
 use crate::state_persistence::AppState;
 use crate::timestamp::current_timestamp;
 
 pub const AGGREGATOR_PATH: &str = "/tmp/.manager.recs";
+/// Where the key tagging the registered apps file is kept, unless
+/// `NODE_SECRET_ENV` points somewhere else
+pub const NODE_SECRET_PATH: &str = "/opt/artisan/node.secret";
+/// Env var overriding `NODE_SECRET_PATH`, for managers that can't write to `/opt/artisan`
+pub const NODE_SECRET_ENV: &str = "ARTISAN_NODE_SECRET";
+const NODE_SECRET_LEN: usize = 32;
+const REGISTERED_APPS_TAG_LEN: usize = 32;
 type ID = Stringy;
 
 // Command Type Enum
@@ -44,6 +69,211 @@ impl fmt::Display for CommandType {
     }
 }
 
+impl CommandType {
+    /// Builds a `Custom` command from a name and arguments, quoting any
+    /// argument that would otherwise be split when parsed back.
+    pub fn custom(name: &str, args: &[&str]) -> Self {
+        let command = CustomCommand {
+            name: Stringy::from(name),
+            args: args.iter().map(|arg| Stringy::from(*arg)).collect(),
+        };
+        CommandType::Custom(command.to_command_string())
+    }
+
+    /// Parses the payload of a `Custom` command. Returns `None` for every other command
+    pub fn parse_custom(&self) -> Option<Result<CustomCommand, ErrorArrayItem>> {
+        match self {
+            CommandType::Custom(cmd) => Some(CustomCommand::parse(cmd)),
+            _ => None,
+        }
+    }
+}
+
+/// A structured `CommandType::Custom` payload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CustomCommand {
+    pub name: Stringy,
+    pub args: Vec<Stringy>,
+}
+
+impl CustomCommand {
+    /// Prefix some senders put in front of the command name
+    const PREFIX: &'static str = "custom:";
+
+    /// Splits a custom command string into a name and arguments.
+    ///
+    /// Tokens are split on whitespace. Single or double quotes group a token
+    /// and a backslash escapes the next character, so
+    /// `custom:deploy "path with spaces" --force` parses to the name `deploy`
+    /// and the args `path with spaces` and `--force`. The `custom:` prefix is optional.
+    pub fn parse(input: &str) -> Result<Self, ErrorArrayItem> {
+        let input: &str = input.trim();
+        let input: &str = input.strip_prefix(Self::PREFIX).unwrap_or(input);
+
+        let mut tokens: Vec<Stringy> = Self::tokenize(input)?.into_iter().map(Stringy::from).collect();
+
+        if tokens.is_empty() {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidType,
+                "Custom command is empty".to_owned(),
+            ));
+        }
+
+        let name: Stringy = tokens.remove(0);
+        Ok(Self { name, args: tokens })
+    }
+
+    fn tokenize(input: &str) -> Result<Vec<String>, ErrorArrayItem> {
+        let mut tokens: Vec<String> = Vec::new();
+        let mut current: String = String::new();
+        let mut in_token: bool = false;
+        let mut quote: Option<char> = None;
+        let mut chars = input.chars();
+
+        while let Some(c) = chars.next() {
+            match (c, quote) {
+                ('\\', Some('\'')) => current.push(c),
+                ('\\', _) => match chars.next() {
+                    Some(escaped) => {
+                        current.push(escaped);
+                        in_token = true;
+                    }
+                    None => {
+                        return Err(ErrorArrayItem::new(
+                            Errors::InvalidType,
+                            "Custom command ends with a dangling escape".to_owned(),
+                        ))
+                    }
+                },
+                (c, Some(q)) if c == q => quote = None,
+                (c, Some(_)) => current.push(c),
+                ('"', None) | ('\'', None) => {
+                    quote = Some(c);
+                    in_token = true;
+                }
+                (c, None) if c.is_whitespace() => {
+                    if in_token {
+                        tokens.push(std::mem::take(&mut current));
+                        in_token = false;
+                    }
+                }
+                (c, None) => {
+                    current.push(c);
+                    in_token = true;
+                }
+            }
+        }
+
+        if quote.is_some() {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidType,
+                "Custom command has an unterminated quote".to_owned(),
+            ));
+        }
+
+        if in_token {
+            tokens.push(current);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Renders the command back into a string `parse` understands
+    pub fn to_command_string(&self) -> String {
+        std::iter::once(&self.name)
+            .chain(self.args.iter())
+            .map(|token| Self::quote(token))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    fn quote(token: &str) -> String {
+        let needs_quotes: bool = token.is_empty()
+            || token
+                .chars()
+                .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\');
+
+        if !needs_quotes {
+            return token.to_owned();
+        }
+
+        let escaped: String = token.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\"", escaped)
+    }
+}
+
+impl fmt::Display for CustomCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_command_string())
+    }
+}
+
+/// Result of comparing an app's expected status with what it reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconciliation {
+    InSync,
+    Drifted { expected: Status, actual: Status },
+}
+
+impl Reconciliation {
+    pub fn is_drifted(&self) -> bool {
+        matches!(self, Reconciliation::Drifted { .. })
+    }
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reconciliation::InSync => write!(f, "{}", "In sync".green()),
+            Reconciliation::Drifted { expected, actual } => {
+                write!(f, "{}: expected {}, actual {}", "Drifted".red().bold(), expected, actual)
+            }
+        }
+    }
+}
+
+/// Returns the apps whose reported status has drifted from their expected status
+pub fn find_drifted(apps: &[AppStatus]) -> Vec<&AppStatus> {
+    apps.iter().filter(|app| app.reconcile().is_drifted()).collect()
+}
+
+/// How many of a set of apps are system apps and how many are client apps
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AppCounts {
+    pub system: u32,
+    pub client: u32,
+}
+
+impl AppCounts {
+    pub fn total(&self) -> u32 {
+        self.system + self.client
+    }
+}
+
+impl fmt::Display for AppCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, {}: {}",
+            "System Apps".bold().cyan(),
+            self.system,
+            "Client Apps".bold().cyan(),
+            self.client
+        )
+    }
+}
+
+/// Counts the system and client apps in a status list
+pub fn classify_apps(apps: &[AppStatus]) -> AppCounts {
+    apps.iter().fold(AppCounts::default(), |mut counts, app| {
+        match app.system_application {
+            true => counts.system += 1,
+            false => counts.client += 1,
+        }
+        counts
+    })
+}
+
 // Different status an application can be in
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq)]
 pub enum Status {
@@ -149,10 +379,35 @@ impl AppStatus {
         serde_json::from_str(json_str)
     }
 
+    /// # Safety
+    ///
+    /// Serializing an `AppStatus` is assumed to never fail, the error case
+    /// is skipped with `unwrap_unchecked`. Prefer `to_json` where that
+    /// isn't acceptable.
     pub unsafe fn to_string(&self) -> String {
         serde_json::to_string(self).unwrap_unchecked()
     }
 
+    /// Compares the status the app reported with the status it's expected to be in.
+    /// An app on its way to the expected status (`Starting` when `Running` is
+    /// expected, `Stopping` when `Stopped` is expected) counts as in sync.
+    pub fn reconcile(&self) -> Reconciliation {
+        let in_sync: bool = match (self.expected_status, self.status) {
+            (expected, actual) if expected == actual => true,
+            (Status::Running, Status::Starting) => true,
+            (Status::Stopped, Status::Stopping) => true,
+            _ => false,
+        };
+
+        match in_sync {
+            true => Reconciliation::InSync,
+            false => Reconciliation::Drifted {
+                expected: self.expected_status,
+                actual: self.status,
+            },
+        }
+    }
+
     pub fn get_id(&self) -> Stringy {
         self.app_id.clone()
     }
@@ -279,6 +534,80 @@ impl fmt::Display for DeregisterApp {
     }
 }
 
+/// Number of samples `MetricsHistory::default` keeps per app
+pub const DEFAULT_METRICS_HISTORY: usize = 60;
+
+/// The last few metric samples for each app, kept in memory for quick trend
+/// rendering. Each app holds at most `capacity` samples, oldest dropped first.
+#[derive(Debug, Clone)]
+pub struct MetricsHistory {
+    capacity: usize,
+    samples: HashMap<ID, VecDeque<(u64, Metrics)>>,
+}
+
+impl Default for MetricsHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_METRICS_HISTORY)
+    }
+}
+
+impl MetricsHistory {
+    /// A `capacity` of `0` is treated as `1`
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, app_id: &ID, timestamp: u64, metrics: Metrics) {
+        let samples = self
+            .samples
+            .entry(app_id.clone())
+            .or_insert_with(|| VecDeque::with_capacity(self.capacity));
+
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back((timestamp, metrics));
+    }
+
+    /// Records the metrics carried by an update, if it has any
+    pub fn record_update(&mut self, update: &UpdateApp) {
+        if let Some(metrics) = &update.metrics {
+            self.record(&update.app_id, update.timestamp, metrics.clone());
+        }
+    }
+
+    /// Samples for `app_id`, oldest first
+    pub fn recent(&self, app_id: &ID) -> Vec<(u64, Metrics)> {
+        self.samples
+            .get(app_id)
+            .map(|samples| samples.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn recent_cpu(&self, app_id: &ID) -> Vec<f32> {
+        self.recent_values(app_id, |metrics| metrics.cpu_usage)
+    }
+
+    pub fn recent_memory(&self, app_id: &ID) -> Vec<f32> {
+        self.recent_values(app_id, |metrics| metrics.memory_usage)
+    }
+
+    /// Drops the history of an app, e.g. once it deregisters
+    pub fn remove(&mut self, app_id: &ID) {
+        self.samples.remove(app_id);
+    }
+
+    fn recent_values(&self, app_id: &ID, value: impl Fn(&Metrics) -> f32) -> Vec<f32> {
+        self.samples
+            .get(app_id)
+            .map(|samples| samples.iter().map(|(_, metrics)| value(metrics)).collect())
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UpdateApp {
     pub app_id: ID,
@@ -318,6 +647,110 @@ impl fmt::Display for UpdateApp {
     }
 }
 
+/// How many apps `send_status_pages` puts in each frame by default
+pub const DEFAULT_STATUS_PAGE_SIZE: usize = 50;
+
+/// One frame of an `AllStatus` answer sent in pages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusPage {
+    pub index: u32,
+    pub apps: Vec<AppStatus>,
+    pub last: bool,
+}
+
+impl fmt::Display for StatusPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, {}: {}, {}: {}",
+            "Page".bold().cyan(),
+            self.index,
+            "Apps".bold().cyan(),
+            self.apps.len(),
+            "Last".bold().cyan(),
+            self.last
+        )
+    }
+}
+
+/// Splits `apps` into pages of at most `page_size` entries. There's always at
+/// least one page, and only the final one has `last` set.
+pub fn paginate_statuses(apps: &[AppStatus], page_size: usize) -> Vec<StatusPage> {
+    if apps.is_empty() {
+        return vec![StatusPage {
+            index: 0,
+            apps: Vec::new(),
+            last: true,
+        }];
+    }
+
+    let chunks: Vec<&[AppStatus]> = apps.chunks(page_size.max(1)).collect();
+    let count: usize = chunks.len();
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| StatusPage {
+            index: index as u32,
+            apps: chunk.to_vec(),
+            last: index + 1 == count,
+        })
+        .collect()
+}
+
+/// Sends `apps` as a run of `StatusPage` frames instead of one large message
+pub async fn send_status_pages<S>(
+    stream: &mut S,
+    apps: &[AppStatus],
+    page_size: usize,
+    proto: Proto,
+) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    for page in paginate_statuses(apps, page_size) {
+        let message = ProtocolMessage::new(Flags::NONE, AppMessage::StatusPage(page))?;
+        let bytes: Vec<u8> = message.format().await?;
+        send_data(stream, bytes, proto).await?;
+    }
+    Ok(())
+}
+
+/// Reads the frames written by `send_status_pages` and puts the list back together
+pub async fn receive_status_pages<S>(stream: &mut S, proto: Proto) -> Result<Vec<AppStatus>, ErrorArrayItem>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let mut apps: Vec<AppStatus> = Vec::new();
+    let mut expected: u32 = 0;
+
+    loop {
+        let message: ProtocolMessage<AppMessage> = receive_message(stream, false, proto).await?;
+        let page: StatusPage = match message.payload {
+            AppMessage::StatusPage(page) => page,
+            other => {
+                return Err(ErrorArrayItem::new(
+                    Errors::InvalidType,
+                    format!("Expected a status page, received: {}", other),
+                ))
+            }
+        };
+
+        if page.index != expected {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidChunkData,
+                format!("Expected status page {}, received {}", expected, page.index),
+            ));
+        }
+
+        apps.extend(page.apps);
+        if page.last {
+            return Ok(apps);
+        }
+        expected += 1;
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum AppMessage {
     Register(RegisterApp),
@@ -325,6 +758,7 @@ pub enum AppMessage {
     Update(UpdateApp),
     Response(CommandResponse),
     Command(Command),
+    StatusPage(StatusPage),
 }
 
 impl fmt::Display for AppMessage {
@@ -335,39 +769,445 @@ impl fmt::Display for AppMessage {
             AppMessage::Update(update) => write!(f, "Update: {}", update),
             AppMessage::Response(response) => write!(f, "Response: {}", response),
             AppMessage::Command(command) => write!(f, "Command: {}", command),
+            AppMessage::StatusPage(page) => write!(f, "Status Page: {}", page),
         }
     }
 }
 
+impl AppMessage {
+    /// Wraps the message in a `ProtocolMessage` with the flags `profile` calls for
+    pub fn into_protocol(self, profile: SecurityProfile) -> io::Result<ProtocolMessage<AppMessage>> {
+        ProtocolMessage::new(profile.flags(), self)
+    }
+}
+
 // Function to save registered apps to a JSON file
 pub async fn save_registered_apps(apps: &[AppStatus]) -> Result<(), ErrorArrayItem> {
+    let key: Vec<u8> = node_secret()?;
+    save_registered_apps_to(apps, AGGREGATOR_PATH, &key).await
+}
+
+/// Saves registered apps to `path`, tagging the plaintext with an HMAC keyed by `key`.
+///
+/// The file holds the hex tag on the first line and the encrypted json after it.
+pub async fn save_registered_apps_to(
+    apps: &[AppStatus],
+    path: &str,
+    key: &[u8],
+) -> Result<(), ErrorArrayItem> {
     let mut file: File = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(AGGREGATOR_PATH)
+        .open(path)
         .map_err(ErrorArrayItem::from)?;
     let data: String = serde_json::to_string_pretty(apps).map_err(ErrorArrayItem::from)?;
+    let tag: String = hex::encode(registered_apps_mac(key, data.as_bytes())?.finalize().into_bytes());
     let encrypted_data: Stringy = encrypt_text(data.into()).await?;
-    match file.write_all(encrypted_data.as_bytes()) {
-        Ok(_) => return Ok(()),
-        Err(err) => return Err(ErrorArrayItem::from(err)),
+    match file.write_all(format!("{}\n{}", tag, encrypted_data).as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(ErrorArrayItem::from(err)),
     }
 }
 
 // Function to load registered apps from a JSON file
 pub async fn load_registered_apps() -> Result<Vec<AppStatus>, ErrorArrayItem> {
-    let mut file: File = File::open(AGGREGATOR_PATH)?;
-    let mut encrypted_data: String = String::new();
-    file.read_to_string(&mut encrypted_data)?;
+    let key: Vec<u8> = node_secret()?;
+    load_registered_apps_from(AGGREGATOR_PATH, &key).await
+}
+
+/// Loads registered apps from `path` and checks the stored HMAC tag.
+///
+/// The encryption can't tell a tampered file from another node's valid blob,
+/// so a mismatched tag is reported as `Errors::InvalidHMACData`. So is a file
+/// without a tag line: files written before tagging was added have to be
+/// adopted on purpose with `migrate_untagged_registered_apps`. A file that
+/// fails to decrypt keeps the decryption error.
+pub async fn load_registered_apps_from(
+    path: &str,
+    key: &[u8],
+) -> Result<Vec<AppStatus>, ErrorArrayItem> {
+    let contents: String = fs::read_to_string(path)?;
+
+    let (tag, encrypted_data) = split_registered_apps_tag(&contents).ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::InvalidHMACData,
+            format!(
+                "Integrity failure: registered apps at {} have no integrity tag, run migrate_untagged_registered_apps if this is a legacy file",
+                path
+            ),
+        )
+    })?;
+
     let data: Stringy = decrypt_text(Stringy::from(encrypted_data)).await?;
+
+    registered_apps_mac(key, data.as_bytes())?
+        .verify_slice(&tag)
+        .map_err(|_| {
+            ErrorArrayItem::new(
+                Errors::InvalidHMACData,
+                "Integrity failure: registered apps tag doesn't match".to_owned(),
+            )
+        })?;
+
     let apps: Vec<AppStatus> = serde_json::from_str(&data)?;
-    // let apps: Vec<RegisterApp> = serde_json::from_reader(reader)?;
     Ok(apps)
 }
 
-// function to register apps with the aggragator
-pub async fn register_app(app: &AppState) -> Result<(), ErrorArrayItem> {
+/// Adopts a registered apps file written before tagging was added by
+/// signing it with the node secret. Meant to be run once, on purpose, by an
+/// operator who trusts the file. Returns the number of apps in it.
+pub async fn migrate_untagged_registered_apps() -> Result<usize, ErrorArrayItem> {
+    let key: Vec<u8> = node_secret()?;
+    migrate_untagged_registered_apps_at(AGGREGATOR_PATH, &key).await
+}
+
+/// `migrate_untagged_registered_apps` for a file at `path`. A file that is
+/// already tagged is only verified, never re-signed.
+pub async fn migrate_untagged_registered_apps_at(
+    path: &str,
+    key: &[u8],
+) -> Result<usize, ErrorArrayItem> {
+    let contents: String = fs::read_to_string(path)?;
+
+    if split_registered_apps_tag(&contents).is_some() {
+        return Ok(load_registered_apps_from(path, key).await?.len());
+    }
+
+    let data: Stringy = decrypt_text(Stringy::from(contents.trim())).await?;
+    let apps: Vec<AppStatus> = serde_json::from_str(&data)?;
+    save_registered_apps_to(&apps, path, key).await?;
+    log!(LogLevel::Info, "Tagged {} legacy registered apps at {}", apps.len(), path);
+    Ok(apps.len())
+}
+
+/// Splits a tagged registered apps file into its tag and ciphertext.
+/// Returns `None` for the legacy untagged format.
+fn split_registered_apps_tag(contents: &str) -> Option<(Vec<u8>, &str)> {
+    let (tag, encrypted_data) = contents.split_once('\n')?;
+    let tag: &str = tag.trim();
+    if tag.len() != REGISTERED_APPS_TAG_LEN * 2 {
+        return None;
+    }
+    let tag: Vec<u8> = hex::decode(tag).ok()?;
+    Some((tag, encrypted_data.trim()))
+}
+
+/// Resets the registered apps file to an empty list, for when every app has
+/// deregistered, so a restarted manager doesn't pick up stale apps
+pub async fn clear_registered_apps() -> Result<(), ErrorArrayItem> {
+    let key: Vec<u8> = node_secret()?;
+    clear_registered_apps_at(AGGREGATOR_PATH, &key).await
+}
+
+/// `clear_registered_apps` for a file at `path`
+pub async fn clear_registered_apps_at(path: &str, key: &[u8]) -> Result<(), ErrorArrayItem> {
+    log!(LogLevel::Info, "Clearing registered apps at {}", path);
+    save_registered_apps_to(&[], path, key).await
+}
+
+/// Where the node secret lives: `NODE_SECRET_ENV` when set, `NODE_SECRET_PATH` otherwise
+pub fn node_secret_path() -> PathType {
+    match std::env::var(NODE_SECRET_ENV) {
+        Ok(path) if !path.is_empty() => PathType::Content(path),
+        _ => PathType::Str(NODE_SECRET_PATH.into()),
+    }
+}
+
+/// Reads the key the registered apps file is tagged with, creating it on first use
+fn node_secret() -> Result<Vec<u8>, ErrorArrayItem> {
+    node_secret_at(&node_secret_path())
+}
+
+/// `load_or_create_node_secret`, with an error that says how to move the secret
+/// when it can't be read or created
+fn node_secret_at(path: &PathType) -> Result<Vec<u8>, ErrorArrayItem> {
+    load_or_create_node_secret(path).map_err(|err| {
+        ErrorArrayItem::new(
+            err.err_type,
+            format!(
+                "Unable to load or create the node secret at {}: {}. Set {} to a writable location",
+                path, err.err_mesg, NODE_SECRET_ENV
+            ),
+        )
+    })
+}
+
+/// Loads the node secret stored hex encoded at `path`. When there is no file
+/// yet a random secret is generated and written there, readable only by the
+/// owner.
+pub fn load_or_create_node_secret(path: &PathType) -> Result<Vec<u8>, ErrorArrayItem> {
+    if path.exists() {
+        let contents: String = fs::read_to_string(path)?;
+        let secret: Vec<u8> = hex::decode(contents.trim()).map_err(|err| {
+            ErrorArrayItem::new(
+                Errors::InvalidKey,
+                format!("Node secret at {} is not valid hex: {}", path, err),
+            )
+        })?;
+        if secret.len() != NODE_SECRET_LEN {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidKey,
+                format!("Node secret at {} is {} bytes, expected {}", path, secret.len(), NODE_SECRET_LEN),
+            ));
+        }
+        return Ok(secret);
+    }
+
+    let mut secret: Vec<u8> = vec![0u8; NODE_SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    write_node_secret(path, &secret)?;
+    log!(LogLevel::Info, "Generated a new node secret at {}", path);
+    Ok(secret)
+}
+
+/// Writes `secret` hex encoded to `path` with mode 0600
+fn write_node_secret(path: &PathType, secret: &[u8]) -> Result<(), ErrorArrayItem> {
+    if let Some(parent) = path.to_path_buf().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file: File = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(hex::encode(secret).as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn registered_apps_mac(key: &[u8], data: &[u8]) -> Result<Hmac<Sha256>, ErrorArrayItem> {
+    let mut mac: Hmac<Sha256> = Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|err| ErrorArrayItem::new(Errors::InvalidKey, err.to_string()))?;
+    mac.update(data);
+    Ok(mac)
+}
+
+/// Coalesces bursts of registered app changes into a single save.
+///
+/// `update` only records the newest list; a background task writes it at
+/// most once per `interval`. Call `flush_now` on shutdown so the last
+/// change isn't lost.
+pub struct RegisteredAppsWriter {
+    inner: Arc<WriterInner>,
+    task: JoinHandle<()>,
+}
+
+struct WriterInner {
+    path: String,
+    key: Vec<u8>,
+    pending: Mutex<Option<Vec<AppStatus>>>,
+    write_lock: Mutex<()>,
+    notify: Notify,
+    writes: AtomicUsize,
+}
+
+impl WriterInner {
+    async fn flush(&self) -> Result<(), ErrorArrayItem> {
+        // Held across the take and the save so an older list can't land after a newer one
+        let _write_guard = self.write_lock.lock().await;
+        let apps: Option<Vec<AppStatus>> = self.pending.lock().await.take();
+
+        if let Some(apps) = apps {
+            save_registered_apps_to(&apps, &self.path, &self.key).await?;
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+}
+
+impl RegisteredAppsWriter {
+    /// Writes to `AGGREGATOR_PATH` using the node secret
+    pub fn new(interval: Duration) -> Result<Self, ErrorArrayItem> {
+        Ok(Self::with_path(AGGREGATOR_PATH, node_secret()?, interval))
+    }
+
+    pub fn with_path(path: &str, key: Vec<u8>, interval: Duration) -> Self {
+        let inner: Arc<WriterInner> = Arc::new(WriterInner {
+            path: path.to_owned(),
+            key,
+            pending: Mutex::new(None),
+            write_lock: Mutex::new(()),
+            notify: Notify::new(),
+            writes: AtomicUsize::new(0),
+        });
+
+        let task_inner: Arc<WriterInner> = inner.clone();
+        let task: JoinHandle<()> = tokio::spawn(async move {
+            loop {
+                task_inner.notify.notified().await;
+                tokio::time::sleep(interval).await;
+                if let Err(err) = task_inner.flush().await {
+                    log!(LogLevel::Error, "Failed to save registered apps: {}", err);
+                }
+            }
+        });
+
+        Self { inner, task }
+    }
+
+    /// Queues `apps` to be written on the next flush
+    pub async fn update(&self, apps: Vec<AppStatus>) {
+        *self.inner.pending.lock().await = Some(apps);
+        self.inner.notify.notify_one();
+    }
+
+    /// Writes any queued change immediately
+    pub async fn flush_now(&self) -> Result<(), ErrorArrayItem> {
+        self.inner.flush().await
+    }
+
+    /// How many times the file has been written
+    pub fn writes(&self) -> usize {
+        self.inner.writes.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for RegisteredAppsWriter {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Where `export_snapshot` and `import_snapshot` find the manager's files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotPaths {
+    pub registered_apps: PathType,
+    pub identity: PathType,
+    pub node_secret: PathType,
+}
+
+impl Default for SnapshotPaths {
+    fn default() -> Self {
+        Self {
+            registered_apps: PathType::Str(AGGREGATOR_PATH.into()),
+            identity: PathType::Str(IDENTITYPATHSTR.into()),
+            node_secret: node_secret_path(),
+        }
+    }
+}
+
+impl SnapshotPaths {
+    /// Keeps every file in `dir`, using the same file names as the defaults
+    pub fn in_dir(dir: &PathType) -> Self {
+        let dir = dir.to_path_buf();
+        Self {
+            registered_apps: PathType::PathBuf(dir.join(".manager.recs")),
+            identity: PathType::PathBuf(dir.join("identity")),
+            node_secret: PathType::PathBuf(dir.join("node.secret")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AggregatorSnapshot {
+    registered_apps: Vec<AppStatus>,
+    identity: Option<String>,
+    #[serde(default)]
+    node_secret: Option<String>,
+    created_at: u64,
+}
+
+/// Bundles the registered apps, the node identity and the node secret into a
+/// single encrypted blob, so a manager can be moved to a new host with one file.
+pub async fn export_snapshot(paths: &SnapshotPaths) -> Result<Vec<u8>, ErrorArrayItem> {
+    let identity: Option<String> = match paths.identity.exists() {
+        true => Some(fs::read_to_string(&paths.identity)?),
+        false => None,
+    };
+
+    let node_secret: Option<Vec<u8>> = match paths.node_secret.exists() {
+        true => Some(load_or_create_node_secret(&paths.node_secret)?),
+        false => None,
+    };
+
+    let registered_apps: Vec<AppStatus> = match (&node_secret, paths.registered_apps.exists()) {
+        (Some(key), true) => {
+            load_registered_apps_from(&paths.registered_apps.to_string(), key).await?
+        }
+        (None, true) => {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidKey,
+                "Registered apps can't be verified without the node secret".to_owned(),
+            ))
+        }
+        (_, false) => Vec::new(),
+    };
+
+    let snapshot: AggregatorSnapshot = AggregatorSnapshot {
+        registered_apps,
+        identity,
+        node_secret: node_secret.map(hex::encode),
+        created_at: current_timestamp(),
+    };
+
+    let data: Vec<u8> = serde_json::to_vec(&snapshot)?;
+    encrypt_tagged(&data, ContentType::Snapshot).await
+}
+
+/// Restores a blob made by `export_snapshot`, overwriting the identity, node
+/// secret and registered apps at `paths`. Returns how many apps were restored.
+pub async fn import_snapshot(bytes: &[u8], paths: &SnapshotPaths) -> Result<usize, ErrorArrayItem> {
+    let data: Vec<u8> = decrypt_expecting(bytes, ContentType::Snapshot).await?;
+    let snapshot: AggregatorSnapshot = serde_json::from_slice(&data)?;
+
+    if let Some(identity) = &snapshot.identity {
+        fs::write(&paths.identity, identity)?;
+    }
+
+    let key: Vec<u8> = match &snapshot.node_secret {
+        Some(secret) => {
+            let secret: Vec<u8> = hex::decode(secret).map_err(|err| {
+                ErrorArrayItem::new(Errors::InvalidKey, format!("Snapshot node secret: {}", err))
+            })?;
+            write_node_secret(&paths.node_secret, &secret)?;
+            secret
+        }
+        None => load_or_create_node_secret(&paths.node_secret)?,
+    };
+
+    save_registered_apps_to(&snapshot.registered_apps, &paths.registered_apps.to_string(), &key)
+        .await?;
+
+    Ok(snapshot.registered_apps.len())
+}
+
+/// What happened when an app tried to register with the aggregator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationOutcome {
+    /// The aggregator accepted the registration
+    Registered,
+    /// The aggregator answered but didn't accept the registration
+    Rejected,
+    /// No aggregator is configured for this app, nothing was sent
+    NotConfigured,
+}
+
+impl RegistrationOutcome {
+    pub fn is_registered(&self) -> bool {
+        *self == RegistrationOutcome::Registered
+    }
+}
+
+impl fmt::Display for RegistrationOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationOutcome::Registered => write!(f, "{}", "Registered".green()),
+            RegistrationOutcome::Rejected => write!(f, "{}", "Rejected".red()),
+            RegistrationOutcome::NotConfigured => write!(f, "{}", "Not Configured".yellow()),
+        }
+    }
+}
+
+/// Registers the app with the aggregator, if one is configured.
+///
+/// `Rejected` means the aggregator read the registration and declined it.
+/// A connection failure or an error status in place of an answer is
+/// returned as an error, since nothing was decided.
+pub async fn register_app(app: &AppState) -> Result<RegistrationOutcome, ErrorArrayItem> {
     log!(LogLevel::Trace, "Registering with aggregator");
     let app = app.clone();
 
@@ -391,23 +1231,801 @@ pub async fn register_app(app: &AppState) -> Result<(), ErrorArrayItem> {
                         AppMessage::Response(command_response) => {
                             if command_response.success {
                                 log!(LogLevel::Trace, "State updated with aggregator !");
+                                return Ok(RegistrationOutcome::Registered);
                             }
+                            log!(LogLevel::Warn, "Aggregator declined the registration");
                         },
                         _ => log!(LogLevel::Warn, "Illegal response recieved while reporting status"),
                     }
 
-                    Ok(())
+                    Ok(RegistrationOutcome::Rejected)
                 },
-                Err(err) => {
-                    log!(LogLevel::Warn, "Updaitng app status with aggregator failed. Recieved {} from server", err);
-                    return  Ok(());
+                Err(status) => {
+                    log!(LogLevel::Warn, "Registering with aggregator failed. Recieved {} from server", status);
+                    Err(ErrorArrayItem::new(
+                        Errors::Network,
+                        format!("Aggregator answered the registration with {}", status),
+                    ))
                 },
             }
         }
 
         None => {
             log!(LogLevel::Trace, "Aggragator not configured");
-            return Ok(())
+            Ok(RegistrationOutcome::NotConfigured)
         },
     }
 }
+
+/// A long lived connection to the aggregator socket.
+///
+/// When the aggregator restarts it recreates its socket and any connection
+/// held open by a manager goes stale. The sender drops the stale stream and
+/// reconnects instead of failing until the manager itself is restarted.
+///
+/// A request is only sent again when none of it reached the socket. Once any
+/// bytes were written the aggregator may have acted on it, so a failure
+/// after that is returned rather than replayed.
+pub struct AggregatorSender {
+    path: String,
+    proto: Proto,
+    stream: Option<UnixStream>,
+    attempts: u8,
+    backoff: Duration,
+}
+
+/// Why an attempt to reach the aggregator failed
+enum SendFailure {
+    /// Nothing of the request was written, so it's safe to send again
+    NotSent(io::Error),
+    /// Some or all of the request was written before the failure
+    MaybeSent(io::Error),
+}
+
+impl AggregatorSender {
+    pub fn new(path: String, proto: Proto) -> Self {
+        Self {
+            path,
+            proto,
+            stream: None,
+            attempts: 5,
+            backoff: Duration::from_millis(250),
+        }
+    }
+
+    /// Sets how many times a send is attempted and the delay between
+    /// attempts. The first reconnect is immediate, after that the delay
+    /// doubles with every failed attempt.
+    pub fn with_retry(mut self, attempts: u8, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_proto(&self) -> Proto {
+        self.proto
+    }
+
+    pub async fn send(&mut self, message: AppMessage) -> Result<CommandResponse, ErrorArrayItem> {
+        let mut delay: Duration = self.backoff;
+        let mut last_error: Option<io::Error> = None;
+
+        for attempt in 1..=self.attempts {
+            if attempt > 2 {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+
+            match self.try_send(message.clone()).await {
+                Ok(response) => return response,
+                Err(SendFailure::NotSent(err)) if Self::is_reconnectable(&err) => {
+                    log!(
+                        LogLevel::Warn,
+                        "Aggregator connection lost ({}), reconnecting. Attempt {} of {}",
+                        err,
+                        attempt,
+                        self.attempts
+                    );
+                    self.stream = None;
+                    last_error = Some(err);
+                }
+                Err(SendFailure::NotSent(err)) | Err(SendFailure::MaybeSent(err)) => {
+                    self.stream = None;
+                    return Err(ErrorArrayItem::from(err));
+                }
+            }
+        }
+
+        Err(ErrorArrayItem::from(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "Aggregator unreachable")
+        })))
+    }
+
+    async fn try_send(
+        &mut self,
+        message: AppMessage,
+    ) -> Result<Result<CommandResponse, ErrorArrayItem>, SendFailure> {
+        if self.stream.is_none() {
+            let stream: UnixStream = UnixStream::connect(&self.path)
+                .await
+                .map_err(SendFailure::NotSent)?;
+            self.stream = Some(stream);
+        }
+
+        let stream: &mut UnixStream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => {
+                return Err(SendFailure::NotSent(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "No stream",
+                )))
+            }
+        };
+
+        let mut tracked: WriteTracker<'_, UnixStream> = WriteTracker::new(stream);
+        let response = match send_message::<WriteTracker<'_, UnixStream>, AppMessage, AppMessage>(
+            &mut tracked,
+            Flags::NONE,
+            message,
+            self.proto,
+            false,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(err) if tracked.written => return Err(SendFailure::MaybeSent(err)),
+            Err(err) => return Err(SendFailure::NotSent(err)),
+        };
+
+        match response {
+            Ok(message) => match message.get_payload().await {
+                AppMessage::Response(command_response) => Ok(Ok(command_response)),
+                _ => Ok(Err(ErrorArrayItem::new(
+                    Errors::Network,
+                    "Illegal response recieved from aggregator".to_owned(),
+                ))),
+            },
+            Err(status) => Ok(Err(ErrorArrayItem::new(
+                Errors::Network,
+                format!("Aggregator responded with {}", status),
+            ))),
+        }
+    }
+
+    /// Errors that mean the stream or socket went away before anything was sent
+    fn is_reconnectable(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::NotFound
+        )
+    }
+}
+
+/// Passes a stream through, noting whether any bytes were written to it
+struct WriteTracker<'a, S> {
+    inner: &'a mut S,
+    written: bool,
+}
+
+impl<'a, S> WriteTracker<'a, S> {
+    fn new(inner: &'a mut S) -> Self {
+        Self {
+            inner,
+            written: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WriteTracker<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTracker<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this: &mut Self = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &result {
+            this.written |= *written > 0;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Aggregator, AppConfig, Environment, StateLocation};
+    use crate::encryption::EncryptionMode;
+    use dusa_collection_utils::version::SoftwareVersion;
+    use simple_comms::protocol::status::ProtocolStatus;
+    use tempfile::TempDir;
+
+    fn app(id: &str, system_application: bool) -> AppStatus {
+        AppStatus {
+            app_id: Stringy::from(id),
+            status: Status::Running,
+            uptime: None,
+            error: None,
+            metrics: None,
+            timestamp: 0,
+            expected_status: Status::Running,
+            system_application,
+        }
+    }
+
+    fn temp_file(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn registered_apps_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        let key = [7u8; 32];
+
+        save_registered_apps_to(&[app("one", true)], &path, &key).await.unwrap();
+        let apps = load_registered_apps_from(&path, &key).await.unwrap();
+        assert_eq!(apps.len(), 1);
+        assert_eq!(apps[0].app_id, Stringy::from("one"));
+    }
+
+    #[tokio::test]
+    async fn swapped_blob_is_an_integrity_failure() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        let key = [7u8; 32];
+
+        save_registered_apps_to(&[app("one", true)], &path, &key).await.unwrap();
+        let original = fs::read_to_string(&path).unwrap();
+        let (tag, _) = original.split_once('\n').unwrap();
+
+        // A valid blob for a different list under the original tag
+        let other: Stringy = encrypt_text(Stringy::from("[]")).await.unwrap();
+        fs::write(&path, format!("{}\n{}", tag, other)).unwrap();
+
+        let err = load_registered_apps_from(&path, &key).await.unwrap_err();
+        assert!(matches!(err.err_type, Errors::InvalidHMACData));
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_an_integrity_failure() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+
+        save_registered_apps_to(&[app("one", true)], &path, &[7u8; 32]).await.unwrap();
+        let err = load_registered_apps_from(&path, &[8u8; 32]).await.unwrap_err();
+        assert!(matches!(err.err_type, Errors::InvalidHMACData));
+    }
+
+    #[tokio::test]
+    async fn undecryptable_file_is_not_an_integrity_failure() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        fs::write(&path, format!("{}\nnot encrypted", "00".repeat(32))).unwrap();
+
+        let err = load_registered_apps_from(&path, &[7u8; 32]).await.unwrap_err();
+        assert!(!matches!(err.err_type, Errors::InvalidHMACData));
+    }
+
+    #[tokio::test]
+    async fn untagged_file_is_an_integrity_failure_until_migrated() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        let key = [7u8; 32];
+
+        let data: String = serde_json::to_string_pretty(&vec![app("old", false)]).unwrap();
+        let legacy: Stringy = encrypt_text(Stringy::from(data)).await.unwrap();
+        fs::write(&path, legacy.to_string()).unwrap();
+
+        let err = load_registered_apps_from(&path, &key).await.unwrap_err();
+        assert!(matches!(err.err_type, Errors::InvalidHMACData));
+        assert_eq!(fs::read_to_string(&path).unwrap(), legacy.to_string());
+
+        assert_eq!(migrate_untagged_registered_apps_at(&path, &key).await.unwrap(), 1);
+        let apps = load_registered_apps_from(&path, &key).await.unwrap();
+        assert_eq!(apps[0].app_id, Stringy::from("old"));
+        // Migrating a tagged file only verifies it
+        assert_eq!(migrate_untagged_registered_apps_at(&path, &key).await.unwrap(), 1);
+        assert!(migrate_untagged_registered_apps_at(&path, &[8u8; 32]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stripping_the_tag_line_is_an_integrity_failure() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        let key = [7u8; 32];
+
+        save_registered_apps_to(&[app("one", true)], &path, &key).await.unwrap();
+        let original = fs::read_to_string(&path).unwrap();
+        let (_, encrypted) = original.split_once('\n').unwrap();
+        for contents in [encrypted.to_owned(), format!("not a tag\n{}", encrypted)] {
+            fs::write(&path, &contents).unwrap();
+
+            let err = load_registered_apps_from(&path, &key).await.unwrap_err();
+            assert!(matches!(err.err_type, Errors::InvalidHMACData));
+            assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        }
+    }
+
+    #[test]
+    fn unwritable_node_secret_path_names_the_override() {
+        let dir = TempDir::new().unwrap();
+        let blocker = dir.path().join("file");
+        fs::write(&blocker, "").unwrap();
+        // The parent is a regular file, so the secret can't be created under it
+        let path = PathType::PathBuf(blocker.join("node.secret"));
+
+        let err = node_secret_at(&path).unwrap_err();
+        assert!(err.err_mesg.to_string().contains(NODE_SECRET_ENV));
+        assert!(err.err_mesg.to_string().contains(&path.to_string()));
+    }
+
+    #[test]
+    fn node_secret_is_generated_once_and_private() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("artisan").join("node.secret"));
+
+        let first = load_or_create_node_secret(&path).unwrap();
+        let second = load_or_create_node_secret(&path).unwrap();
+        assert_eq!(first.len(), NODE_SECRET_LEN);
+        assert_eq!(first, second);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o600);
+    }
+
+    /// How the test aggregator answers a request
+    #[derive(Clone, Copy)]
+    enum Reply {
+        /// A `CommandResponse` with the given success
+        Response(bool),
+        /// A response carrying this header status
+        Status(ProtocolStatus),
+        /// Hang up without answering
+        Nothing,
+    }
+
+    /// Answers requests on `path` until aborted, counting the ones it received.
+    /// Connections are handled inline so aborting drops them with the listener.
+    fn spawn_aggregator(path: &str, received: Arc<AtomicUsize>, reply: Reply) -> JoinHandle<()> {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                while receive_message::<UnixStream, AppMessage>(&mut stream, false, Proto::UNIX)
+                    .await
+                    .is_ok()
+                {
+                    received.fetch_add(1, Ordering::SeqCst);
+                    let (success, status) = match reply {
+                        Reply::Response(success) => (success, None),
+                        Reply::Status(status) => (true, Some(status)),
+                        Reply::Nothing => break,
+                    };
+
+                    let response = AppMessage::Response(CommandResponse {
+                        app_id: Stringy::from("test"),
+                        command_type: CommandType::Status,
+                        success,
+                        message: None,
+                    });
+                    let mut message = ProtocolMessage::new(Flags::NONE, response).unwrap();
+                    if let Some(status) = status {
+                        message.header.status = status.bits();
+                    }
+                    let bytes = message.format().await.unwrap();
+                    send_data(&mut stream, bytes, Proto::UNIX).await.unwrap();
+                }
+            }
+        })
+    }
+
+    fn status_request() -> AppMessage {
+        AppMessage::Command(Command {
+            app_id: Stringy::from("test"),
+            command_type: CommandType::Status,
+            timestamp: 0,
+        })
+    }
+
+    async fn stop_aggregator(server: JoinHandle<()>, path: &str) {
+        server.abort();
+        let _ = server.await;
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sender_recovers_when_the_socket_is_recreated() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, "aggregator.sock");
+        let received = Arc::new(AtomicUsize::new(0));
+        let mut sender =
+            AggregatorSender::new(path.clone(), Proto::UNIX).with_retry(3, Duration::from_millis(10));
+
+        let server = spawn_aggregator(&path, received.clone(), Reply::Response(true));
+        assert!(sender.send(status_request()).await.unwrap().success);
+
+        // The held stream is now stale, the sender has to reconnect on its own
+        stop_aggregator(server, &path).await;
+        let server = spawn_aggregator(&path, received.clone(), Reply::Response(true));
+        assert!(sender.send(status_request()).await.unwrap().success);
+
+        stop_aggregator(server, &path).await;
+        assert!(sender.send(status_request()).await.is_err());
+
+        let _server = spawn_aggregator(&path, received.clone(), Reply::Response(true));
+        assert!(sender.send(status_request()).await.unwrap().success);
+        assert_eq!(received.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn delivered_request_is_not_replayed() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, "aggregator.sock");
+        let received = Arc::new(AtomicUsize::new(0));
+        let mut sender =
+            AggregatorSender::new(path.clone(), Proto::UNIX).with_retry(3, Duration::from_millis(10));
+
+        let _server = spawn_aggregator(&path, received.clone(), Reply::Nothing);
+        assert!(sender.send(status_request()).await.is_err());
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    fn app_state(aggregator: Option<Aggregator>) -> AppState {
+        AppState {
+            name: String::from("register_test"),
+            version: SoftwareVersion::dummy(),
+            data: String::new(),
+            last_updated: 0,
+            event_counter: 0,
+            is_active: true,
+            error_log: vec![],
+            config: AppConfig {
+                app_name: "register_test".into(),
+                version: String::from("1.0.0"),
+                max_ram_usage: 16,
+                max_cpu_usage: 16,
+                environment: Environment::Development,
+                debug_mode: false,
+                log_level: LogLevel::Info,
+                git: None,
+                database: None,
+                aggregator,
+                state_location: StateLocation::Tmp,
+                encryption: EncryptionMode::Recs,
+            },
+            system_application: false,
+            log_level_override: None,
+        }
+    }
+
+    async fn register_against(reply: Reply) -> Result<RegistrationOutcome, ErrorArrayItem> {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, "aggregator.sock");
+        let _server = spawn_aggregator(&path, Arc::new(AtomicUsize::new(0)), reply);
+
+        let aggregator = Aggregator {
+            socket_path: path,
+            socket_permission: None,
+        };
+        register_app(&app_state(Some(aggregator))).await
+    }
+
+    #[tokio::test]
+    async fn unconfigured_aggregator_is_reported() {
+        let outcome = register_app(&app_state(None)).await.unwrap();
+        assert_eq!(outcome, RegistrationOutcome::NotConfigured);
+    }
+
+    #[tokio::test]
+    async fn configured_aggregator_registers_or_rejects() {
+        let outcome = register_against(Reply::Response(true)).await.unwrap();
+        assert_eq!(outcome, RegistrationOutcome::Registered);
+
+        let outcome = register_against(Reply::Response(false)).await.unwrap();
+        assert_eq!(outcome, RegistrationOutcome::Rejected);
+    }
+
+    #[tokio::test]
+    async fn error_status_is_not_a_rejection() {
+        // A sidegrade request is refused by `send_message`, so no answer was given
+        let err = register_against(Reply::Status(ProtocolStatus::SIDEGRADE)).await.unwrap_err();
+        assert_eq!(err.err_type, Errors::Network);
+        assert!(err.err_mesg.to_string().contains("registration"));
+    }
+
+    #[tokio::test]
+    async fn unreachable_aggregator_is_an_error() {
+        let dir = TempDir::new().unwrap();
+        let aggregator = Aggregator {
+            socket_path: temp_file(&dir, "missing.sock"),
+            socket_permission: None,
+        };
+        assert!(register_app(&app_state(Some(aggregator))).await.is_err());
+    }
+
+    fn tokens(command: &CustomCommand) -> Vec<String> {
+        command.args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn custom_command_splits_quoted_arguments() {
+        let command = CustomCommand::parse(r#"custom:deploy "path with spaces" --force"#).unwrap();
+        assert_eq!(command.name, Stringy::from("deploy"));
+        assert_eq!(tokens(&command), vec!["path with spaces", "--force"]);
+
+        let command = CustomCommand::parse(r#"rebuild 'it''s' a\ b "say \"hi\"" ''"#).unwrap();
+        assert_eq!(command.name, Stringy::from("rebuild"));
+        assert_eq!(tokens(&command), vec!["its", "a b", r#"say "hi""#, ""]);
+    }
+
+    #[test]
+    fn custom_command_rejects_malformed_input() {
+        assert!(CustomCommand::parse("").is_err());
+        assert!(CustomCommand::parse("custom:   ").is_err());
+        assert!(CustomCommand::parse(r#"deploy "unterminated"#).is_err());
+        assert!(CustomCommand::parse(r"deploy trailing\").is_err());
+    }
+
+    #[test]
+    fn custom_command_round_trips_through_command_type() {
+        let command = CommandType::custom("deploy", &["path with spaces", "--force", r#"a"b\c"#, ""]);
+        let parsed = command.parse_custom().unwrap().unwrap();
+
+        assert_eq!(parsed.name, Stringy::from("deploy"));
+        assert_eq!(tokens(&parsed), vec!["path with spaces", "--force", r#"a"b\c"#, ""]);
+        assert!(CommandType::Restart.parse_custom().is_none());
+    }
+
+    fn deregister() -> AppMessage {
+        AppMessage::Deregister(DeregisterApp {
+            app_id: Stringy::from("app"),
+            deregistration_timestamp: 0,
+        })
+    }
+
+    #[test]
+    fn tcp_messages_are_encrypted_and_signed() {
+        let message = deregister().into_protocol(SecurityProfile::Tcp).unwrap();
+        let flags = Flags::from_bits_truncate(message.header.flags);
+
+        assert!(flags.contains(Flags::ENCRYPTED | Flags::SIGNATURE));
+    }
+
+    #[test]
+    fn unix_messages_go_out_plain() {
+        let message = deregister().into_protocol(SecurityProfile::Unix).unwrap();
+
+        assert_eq!(Flags::from_bits_truncate(message.header.flags), Flags::NONE);
+        assert_eq!(SecurityProfile::from(Proto::UNIX), SecurityProfile::Unix);
+        assert_eq!(SecurityProfile::from(Proto::TCP), SecurityProfile::Tcp);
+    }
+
+    #[tokio::test]
+    async fn snapshot_moves_registered_apps_to_a_fresh_dir() {
+        let source_dir = TempDir::new().unwrap();
+        let source = SnapshotPaths::in_dir(&PathType::PathBuf(source_dir.path().to_path_buf()));
+        let key = load_or_create_node_secret(&source.node_secret).unwrap();
+        let apps = [app("one", true), app("two", false)];
+        save_registered_apps_to(&apps, &source.registered_apps.to_string(), &key)
+            .await
+            .unwrap();
+        fs::write(&source.identity, "identity").unwrap();
+
+        let bytes = export_snapshot(&source).await.unwrap();
+
+        let target_dir = TempDir::new().unwrap();
+        let target = SnapshotPaths::in_dir(&PathType::PathBuf(target_dir.path().to_path_buf()));
+        assert_eq!(import_snapshot(&bytes, &target).await.unwrap(), 2);
+
+        let imported_key = load_or_create_node_secret(&target.node_secret).unwrap();
+        assert_eq!(imported_key, key);
+        let apps = load_registered_apps_from(&target.registered_apps.to_string(), &imported_key)
+            .await
+            .unwrap();
+        let ids: Vec<Stringy> = apps.into_iter().map(|app| app.app_id).collect();
+        assert_eq!(ids, vec![Stringy::from("one"), Stringy::from("two")]);
+        assert_eq!(fs::read_to_string(&target.identity).unwrap(), "identity");
+    }
+
+    #[tokio::test]
+    async fn other_envelopes_are_not_imported_as_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let paths = SnapshotPaths::in_dir(&PathType::PathBuf(dir.path().to_path_buf()));
+        let bytes = encrypt_tagged(b"{}", ContentType::AppState).await.unwrap();
+
+        assert!(import_snapshot(&bytes, &paths).await.is_err());
+        assert!(!paths.registered_apps.exists());
+    }
+
+    fn app_with_status(id: &str, expected_status: Status, status: Status) -> AppStatus {
+        AppStatus {
+            status,
+            expected_status,
+            ..app(id, false)
+        }
+    }
+
+    #[test]
+    fn drifted_app_is_detected() {
+        let apps = vec![
+            app_with_status("running", Status::Running, Status::Running),
+            app_with_status("starting", Status::Running, Status::Starting),
+            app_with_status("stopping", Status::Stopped, Status::Stopping),
+            app_with_status("crashed", Status::Running, Status::Stopped),
+        ];
+
+        let drifted = find_drifted(&apps);
+        assert_eq!(drifted.len(), 1);
+        assert_eq!(drifted[0].app_id, Stringy::from("crashed"));
+        assert_eq!(
+            drifted[0].reconcile(),
+            Reconciliation::Drifted {
+                expected: Status::Running,
+                actual: Status::Stopped,
+            }
+        );
+        assert_eq!(apps[1].reconcile(), Reconciliation::InSync);
+    }
+
+    #[tokio::test]
+    async fn burst_of_updates_is_coalesced() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        let key = [7u8; 32];
+        let writer = RegisteredAppsWriter::with_path(&path, key.to_vec(), Duration::from_millis(50));
+
+        for i in 0..100 {
+            let apps: Vec<AppStatus> = (0..=i).map(|n| app(&n.to_string(), false)).collect();
+            writer.update(apps).await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        writer.flush_now().await.unwrap();
+
+        assert!(writer.writes() > 0);
+        assert!(writer.writes() < 10, "{} writes", writer.writes());
+        let apps = load_registered_apps_from(&path, &key).await.unwrap();
+        assert_eq!(apps.len(), 100);
+        assert_eq!(apps[99].app_id, Stringy::from("99"));
+    }
+
+    #[tokio::test]
+    async fn flush_now_writes_without_waiting() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        let key = [7u8; 32];
+        let writer = RegisteredAppsWriter::with_path(&path, key.to_vec(), Duration::from_secs(60));
+
+        writer.update(vec![app("one", true)]).await;
+        writer.flush_now().await.unwrap();
+        assert_eq!(writer.writes(), 1);
+        assert_eq!(load_registered_apps_from(&path, &key).await.unwrap().len(), 1);
+
+        // Nothing queued, nothing written
+        writer.flush_now().await.unwrap();
+        assert_eq!(writer.writes(), 1);
+    }
+
+    #[tokio::test]
+    async fn cleared_registered_apps_load_empty() {
+        let dir = TempDir::new().unwrap();
+        let path = temp_file(&dir, ".manager.recs");
+        let key = [7u8; 32];
+
+        save_registered_apps_to(&[app("one", true), app("two", false)], &path, &key)
+            .await
+            .unwrap();
+        clear_registered_apps_at(&path, &key).await.unwrap();
+
+        assert!(load_registered_apps_from(&path, &key).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_status_arrives_across_pages() {
+        let apps: Vec<AppStatus> = (0..200).map(|n| app(&n.to_string(), false)).collect();
+        let pages = paginate_statuses(&apps, DEFAULT_STATUS_PAGE_SIZE);
+        assert_eq!(pages.len(), 4);
+        assert!(pages.iter().all(|page| page.apps.len() == DEFAULT_STATUS_PAGE_SIZE));
+        assert_eq!(pages.iter().filter(|page| page.last).count(), 1);
+
+        let (mut server, mut client) = tokio::io::duplex(64 * 1024);
+        let (sent, received) = tokio::join!(
+            send_status_pages(&mut server, &apps, DEFAULT_STATUS_PAGE_SIZE, Proto::UNIX),
+            receive_status_pages(&mut client, Proto::UNIX),
+        );
+        sent.unwrap();
+
+        let ids: Vec<Stringy> = received.unwrap().into_iter().map(|app| app.app_id).collect();
+        let expected: Vec<Stringy> = apps.into_iter().map(|app| app.app_id).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    async fn empty_status_is_a_single_last_page() {
+        let (mut server, mut client) = tokio::io::duplex(64 * 1024);
+        send_status_pages(&mut server, &[], DEFAULT_STATUS_PAGE_SIZE, Proto::UNIX)
+            .await
+            .unwrap();
+
+        assert!(receive_status_pages(&mut client, Proto::UNIX).await.unwrap().is_empty());
+    }
+
+    fn update(id: &str, timestamp: u64, metrics: Option<Metrics>) -> UpdateApp {
+        UpdateApp {
+            app_id: Stringy::from(id),
+            error: None,
+            metrics,
+            status: Status::Running,
+            timestamp,
+        }
+    }
+
+    fn metrics(cpu_usage: f32) -> Metrics {
+        Metrics {
+            cpu_usage,
+            memory_usage: cpu_usage * 10.0,
+            other: None,
+        }
+    }
+
+    #[test]
+    fn metrics_history_keeps_only_the_last_samples() {
+        let mut history = MetricsHistory::new(3);
+        let id = Stringy::from("app");
+        for n in 0..6 {
+            history.record_update(&update("app", n, Some(metrics(n as f32))));
+        }
+        history.record_update(&update("app", 6, None));
+        history.record_update(&update("other", 7, Some(metrics(99.0))));
+
+        assert_eq!(history.recent_cpu(&id), vec![3.0, 4.0, 5.0]);
+        assert_eq!(history.recent_memory(&id), vec![30.0, 40.0, 50.0]);
+        let timestamps: Vec<u64> = history.recent(&id).into_iter().map(|(at, _)| at).collect();
+        assert_eq!(timestamps, vec![3, 4, 5]);
+        assert_eq!(history.recent_cpu(&Stringy::from("other")), vec![99.0]);
+
+        history.remove(&id);
+        assert!(history.recent_cpu(&id).is_empty());
+    }
+
+    #[test]
+    fn mixed_apps_are_counted_by_kind() {
+        let apps = vec![
+            app("manager", true),
+            app("site-one", false),
+            app("logger", true),
+            app("site-two", false),
+            app("site-three", false),
+        ];
+
+        let counts = classify_apps(&apps);
+        assert_eq!(counts, AppCounts { system: 2, client: 3 });
+        assert_eq!(counts.total(), 5);
+        assert_eq!(classify_apps(&[]), AppCounts::default());
+    }
+}
@@ -1,16 +1,22 @@
 // Frequently used functions
+use std::{fmt, io};
+
 use dusa_collection_utils::{
     errors::{ErrorArrayItem, Errors},
     log,
     log::{set_log_level, LogLevel},
     types::PathType,
+    version::SoftwareVersion,
 };
-use simple_comms::{network::send_receive::send_message, protocol::{flags::Flags, proto::Proto}};
+use simple_comms::protocol::{flags::Flags, proto::Proto};
 use tokio::net::UnixStream;
 
 use crate::{
     aggregator::{AppMessage, Metrics, Status, UpdateApp},
-    state_persistence::{AppState, StatePersistence},
+    config::AppConfig,
+    identity::{Identifier, IDENTITYPATHSTR},
+    network::{send_message_with_timeout, DEFAULT_RESPONSE_TIMEOUT},
+    state_persistence::{AppState, PersistenceError, StatePersistence},
     timestamp::current_timestamp,
 };
 
@@ -31,7 +37,7 @@ pub async fn update_state(state: &mut AppState, path: &PathType, metrics: Option
             });
 
             if let Ok(mut stream) = UnixStream::connect(agg.socket_path.clone()).await {
-                if let Ok(message) = send_message::<UnixStream, AppMessage, AppMessage>(&mut stream, Flags::NONE, app_message, Proto::UNIX, true).await {
+                if let Ok(message) = send_message_with_timeout::<UnixStream, AppMessage, AppMessage>(&mut stream, Flags::NONE, app_message, Proto::UNIX, true, DEFAULT_RESPONSE_TIMEOUT).await {
 
                     match message {
                         Ok(response) => {
@@ -65,16 +71,50 @@ pub async fn update_state(state: &mut AppState, path: &PathType, metrics: Option
     }
 }
 
-// Update the state file in the case of a un handled error
-pub async fn wind_down_state(state: &mut AppState, state_path: &PathType) {
+/// Why an application is winding down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// A clean shutdown was asked for
+    Requested,
+    /// The application hit an unrecoverable error
+    Crashed,
+    /// Stopping to pick up a new configuration
+    ConfigReload,
+    /// Stopping to be replaced by a newer build
+    Upgrade,
+}
+
+impl ShutdownReason {
+    /// Only crashes are failures, everything else is an expected stop
+    pub fn is_clean(&self) -> bool {
+        !matches!(self, ShutdownReason::Crashed)
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownReason::Requested => write!(f, "Requested"),
+            ShutdownReason::Crashed => write!(f, "Crashed"),
+            ShutdownReason::ConfigReload => write!(f, "ConfigReload"),
+            ShutdownReason::Upgrade => write!(f, "Upgrade"),
+        }
+    }
+}
+
+// Update the state file when the application is stopping. Only a crash is
+// recorded in the error log, clean stops just note the reason in data
+pub async fn wind_down_state(state: &mut AppState, state_path: &PathType, reason: ShutdownReason) {
     state.is_active = false;
-    state.data = String::from("Terminated");
+    state.data = format!("Terminated: {}", reason);
     state.last_updated = current_timestamp();
-    state.error_log.push(ErrorArrayItem::new(
-        Errors::GeneralError,
-        "Wind down requested check logs".to_owned(),
-    ));
-    update_state(state, &state_path, None).await;
+    if !reason.is_clean() {
+        state.error_log.push(ErrorArrayItem::new(
+            Errors::GeneralError,
+            "Wind down requested check logs".to_owned(),
+        ));
+    }
+    update_state(state, state_path, None).await;
 }
 
 // Log an error and update the state
@@ -91,3 +131,209 @@ pub fn debug_log_set(state: &AppState) {
         set_log_level(LogLevel::Debug);
     }
 }
+
+/// Everything a service needs once it has booted
+pub struct BootstrapResult {
+    pub config: AppConfig,
+    pub identity: Identifier,
+    pub state: AppState,
+    pub state_path: PathType,
+}
+
+/// Runs the usual service boot sequence: load the config, set the log level,
+/// load or generate the node identity and load or create the app state.
+/// The state is marked active and persisted before returning.
+///
+/// Only a missing identity or state file is created fresh. One that exists
+/// but can't be read, decrypted or parsed is returned as an error and left
+/// untouched.
+pub async fn bootstrap(app_name: &str) -> Result<BootstrapResult, ErrorArrayItem> {
+    let mut config: AppConfig = AppConfig::new().map_err(|err| {
+        ErrorArrayItem::new(Errors::ConfigParsing, format!("Failed to load config: {}", err))
+    })?;
+    config.app_name = app_name.into();
+
+    bootstrap_with(config, &PathType::Str(IDENTITYPATHSTR.into())).await
+}
+
+/// `bootstrap` with an already loaded config and a custom identity path
+pub async fn bootstrap_with(
+    config: AppConfig,
+    identity_path: &PathType,
+) -> Result<BootstrapResult, ErrorArrayItem> {
+    set_log_level(config.log_level);
+    if config.debug_mode {
+        set_log_level(LogLevel::Debug);
+    }
+
+    let identity: Identifier = match Identifier::try_load(identity_path).await {
+        Ok(identity) => identity,
+        Err(PersistenceError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            log!(LogLevel::Info, "No identity at {}, generating a new one", identity_path);
+            let identity: Identifier = Identifier::new().await?;
+            identity.try_save(identity_path).await?;
+            identity
+        }
+        Err(err) => {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Identity file {} is unreadable or corrupt: {}", identity_path, err),
+            ));
+        }
+    };
+
+    if !identity.verify().await {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("Identity at {} failed verification", identity_path),
+        ));
+    }
+
+    let state_path: PathType = StatePersistence::get_state_path(&config);
+    let mut state: AppState = match StatePersistence::try_load_with_mode(&state_path, config.encryption).await {
+        Ok(mut state) => {
+            log!(LogLevel::Debug, "Loaded existing state from {}", state_path);
+            state.config = config.clone();
+            state
+        }
+        Err(PersistenceError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            log!(LogLevel::Info, "No state at {}, creating new state", state_path);
+            AppState {
+                name: config.app_name.to_string(),
+                version: config.get_version().unwrap_or(SoftwareVersion::dummy()),
+                data: String::new(),
+                last_updated: current_timestamp(),
+                event_counter: 0,
+                is_active: false,
+                error_log: vec![],
+                config: config.clone(),
+                system_application: false,
+                log_level_override: None,
+            }
+        }
+        Err(err) => {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("State file {} is unreadable or corrupt: {}", state_path, err),
+            ));
+        }
+    };
+
+    state.is_active = true;
+    state.data = String::from("Initialized");
+    update_state(&mut state, &state_path, None).await;
+
+    Ok(BootstrapResult {
+        config,
+        identity,
+        state,
+        state_path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Environment, StateLocation};
+    use crate::encryption::EncryptionMode;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn config(dir: &TempDir) -> AppConfig {
+        AppConfig {
+            app_name: "bootstrap_test".into(),
+            version: String::from("1.0.0"),
+            max_ram_usage: 16,
+            max_cpu_usage: 16,
+            environment: Environment::Development,
+            debug_mode: false,
+            log_level: LogLevel::Error,
+            git: None,
+            database: None,
+            aggregator: None,
+            state_location: StateLocation::Persistent(PathType::PathBuf(dir.path().to_path_buf())),
+            encryption: EncryptionMode::Recs,
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_created() {
+        let dir = TempDir::new().unwrap();
+        let identity_path = PathType::PathBuf(dir.path().join("identity"));
+
+        let result = bootstrap_with(config(&dir), &identity_path).await.unwrap();
+
+        assert!(identity_path.exists());
+        assert!(result.state_path.exists());
+        assert!(result.state.is_active);
+        assert_eq!(Identifier::try_load(&identity_path).await.unwrap().id, result.identity.id);
+    }
+
+    #[tokio::test]
+    async fn corrupt_identity_is_an_error_and_kept() {
+        let dir = TempDir::new().unwrap();
+        let identity_path = PathType::PathBuf(dir.path().join("identity"));
+        fs::write(&identity_path, "not an identity").unwrap();
+
+        assert!(bootstrap_with(config(&dir), &identity_path).await.is_err());
+        assert_eq!(fs::read_to_string(&identity_path).unwrap(), "not an identity");
+    }
+
+    #[tokio::test]
+    async fn corrupt_state_is_an_error_and_kept() {
+        let dir = TempDir::new().unwrap();
+        let identity_path = PathType::PathBuf(dir.path().join("identity"));
+        let config = config(&dir);
+        let state_path = StatePersistence::get_state_path(&config);
+        fs::write(&state_path, "not a state").unwrap();
+
+        assert!(bootstrap_with(config, &identity_path).await.is_err());
+        assert_eq!(fs::read_to_string(&state_path).unwrap(), "not a state");
+    }
+
+    #[tokio::test]
+    async fn unreadable_identity_is_an_error() {
+        let dir = TempDir::new().unwrap();
+        let identity_path = PathType::PathBuf(dir.path().join("identity"));
+        // A directory can't be read as a file, standing in for EACCES when running as root
+        fs::create_dir(&identity_path).unwrap();
+
+        assert!(bootstrap_with(config(&dir), &identity_path).await.is_err());
+        assert!(identity_path.to_path_buf().is_dir());
+    }
+
+    #[tokio::test]
+    async fn clean_shutdown_leaves_error_log_alone() {
+        let dir = TempDir::new().unwrap();
+        let identity_path = PathType::PathBuf(dir.path().join("identity"));
+        let BootstrapResult { mut state, state_path, .. } =
+            bootstrap_with(config(&dir), &identity_path).await.unwrap();
+        let errors = state.error_log.len();
+
+        wind_down_state(&mut state, &state_path, ShutdownReason::Requested).await;
+
+        assert!(!state.is_active);
+        assert_eq!(state.data, "Terminated: Requested");
+        assert_eq!(state.error_log.len(), errors);
+        let saved = StatePersistence::try_load(&state_path).await.unwrap();
+        assert_eq!(saved.data, "Terminated: Requested");
+        assert_eq!(saved.error_log.len(), errors);
+    }
+
+    #[tokio::test]
+    async fn crash_is_recorded_as_an_error() {
+        let dir = TempDir::new().unwrap();
+        let identity_path = PathType::PathBuf(dir.path().join("identity"));
+        let BootstrapResult { mut state, state_path, .. } =
+            bootstrap_with(config(&dir), &identity_path).await.unwrap();
+        let errors = state.error_log.len();
+
+        wind_down_state(&mut state, &state_path, ShutdownReason::Crashed).await;
+
+        assert!(!state.is_active);
+        assert_eq!(state.data, "Terminated: Crashed");
+        assert_eq!(state.error_log.len(), errors + 1);
+        let saved = StatePersistence::try_load(&state_path).await.unwrap();
+        assert_eq!(saved.error_log.len(), errors + 1);
+    }
+}
@@ -11,6 +11,12 @@ pub struct ToggleControl {
     notify_resume: Notify,
 }
 
+impl Default for ToggleControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ToggleControl {
     pub fn new() -> Self {
         Self {
@@ -50,6 +56,6 @@ impl ToggleControl {
     }
 
     pub async fn is_paused(&self) -> bool {
-        return self.paused.load(Ordering::SeqCst);
+        self.paused.load(Ordering::SeqCst)
     }
 }
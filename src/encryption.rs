@@ -1,20 +1,26 @@
 use dusa_collection_utils::log;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Notify;
 use {
     dusa_collection_utils::{
         errors::{ErrorArrayItem, Errors, UnifiedResult},
         log::LogLevel,
         stringy::Stringy,
+        types::PathType,
     },
     recs::{decrypt_raw, encrypt_raw, house_keeping, initialize},
     std::{
+        fmt,
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
         sync::{
             atomic::{AtomicBool, Ordering},
             Arc,
         },
         time::Duration,
     },
-    tokio::time::sleep,
+    tokio::{io::AsyncWriteExt, time::sleep},
 };
 
 lazy_static::lazy_static! {
@@ -34,7 +40,7 @@ pub async fn encrypt_text(data: Stringy) -> Result<Stringy, ErrorArrayItem> {
 
 pub async fn decrypt_text(data: Stringy) -> Result<Stringy, ErrorArrayItem> {
     let data_bytes: &[u8] = data.as_bytes();
-    let decrypted_bytes: Vec<u8> = decrypt_data(&data_bytes).await.uf_unwrap()?;
+    let decrypted_bytes: Vec<u8> = decrypt_data(data_bytes).await.uf_unwrap()?;
     let decrypted_string: String = String::from_utf8(decrypted_bytes)?;
     let decrypted_stringy: Stringy = Stringy::Immutable(Arc::<str>::from(decrypted_string));
 
@@ -75,10 +81,10 @@ pub async fn encrypt_data(data: &[u8]) -> UnifiedResult<Vec<u8>> {
         }
     }
 
-    return UnifiedResult::new(Err(ErrorArrayItem::new(
+    UnifiedResult::new(Err(ErrorArrayItem::new(
         Errors::GeneralError,
         "Attempted to many times to access recs, system busy".to_owned(),
-    )));
+    )))
 }
 
 pub async fn decrypt_data(data: &[u8]) -> UnifiedResult<Vec<u8>> {
@@ -119,14 +125,7 @@ pub async fn decrypt_data(data: &[u8]) -> UnifiedResult<Vec<u8>> {
         
         let key = cleaned_parts[1].to_string();
         let encrypted_data = cleaned_parts[0].to_string();
-        let count = match cleaned_parts[2].parse::<usize>() {
-            Ok(c) => c,
-            Err(_e) => {
-                // log!(LogLevel::Error, "Invalid count value: {}", e);
-                1
-                // return UnifiedResult::new(Err(ErrorArrayItem::from(e)));
-            }
-        };
+        let count = cleaned_parts[2].parse::<usize>().unwrap_or(1);
 
         match decrypt_raw(encrypted_data, key, count).uf_unwrap() {
             Ok(data) => return UnifiedResult::new(Ok(data)),
@@ -134,10 +133,375 @@ pub async fn decrypt_data(data: &[u8]) -> UnifiedResult<Vec<u8>> {
         }
     }
 
-    return UnifiedResult::new(Err(ErrorArrayItem::new(
+    UnifiedResult::new(Err(ErrorArrayItem::new(
         Errors::GeneralError,
         "Attempted to many times to access recs, system busy".to_owned(),
-    )));
+    )))
+}
+
+/// Magic prefix identifying a tagged envelope
+const ENVELOPE_MAGIC: &[u8; 3] = b"AHE";
+/// Magic prefix identifying an unencrypted envelope from `EncryptionMode::None`
+const PLAINTEXT_MAGIC: &[u8; 3] = b"AHP";
+/// Current envelope format version
+const ENVELOPE_VERSION: u8 = b'1';
+/// Magic + version + content type
+const ENVELOPE_HEADER_LEN: usize = 5;
+/// Largest payload accepted into an envelope, 64 MiB
+pub const MAX_ENVELOPE_PAYLOAD: usize = 64 * 1024 * 1024;
+/// Largest encrypted envelope accepted for decryption. The ciphertext is
+/// hex, so twice the plaintext, plus room for the key and count.
+const MAX_ENVELOPE_CIPHERTEXT: usize = 2 * (MAX_ENVELOPE_PAYLOAD + ENVELOPE_HEADER_LEN) + 1024;
+
+/// What kind of artifact an encrypted envelope holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ContentType {
+    AppState,
+    GitCredentials,
+    Identity,
+    RegisteredApps,
+    Environment,
+    Snapshot,
+    Generic,
+}
+
+impl ContentType {
+    fn to_tag(self) -> u8 {
+        match self {
+            ContentType::AppState => b'S',
+            ContentType::GitCredentials => b'C',
+            ContentType::Identity => b'I',
+            ContentType::RegisteredApps => b'R',
+            ContentType::Environment => b'E',
+            ContentType::Snapshot => b'N',
+            ContentType::Generic => b'G',
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            b'S' => Some(ContentType::AppState),
+            b'C' => Some(ContentType::GitCredentials),
+            b'I' => Some(ContentType::Identity),
+            b'R' => Some(ContentType::RegisteredApps),
+            b'E' => Some(ContentType::Environment),
+            b'N' => Some(ContentType::Snapshot),
+            b'G' => Some(ContentType::Generic),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ContentType::AppState => "AppState",
+            ContentType::GitCredentials => "GitCredentials",
+            ContentType::Identity => "Identity",
+            ContentType::RegisteredApps => "RegisteredApps",
+            ContentType::Environment => "Environment",
+            ContentType::Snapshot => "Snapshot",
+            ContentType::Generic => "Generic",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Encrypts `data` with a small header (magic, format version and content type)
+/// prepended to the plaintext, so loaders can tell what a blob is before
+/// trying to deserialize it. The header is plain ascii to keep the payload
+/// valid for the text based recs api.
+pub async fn encrypt_tagged(data: &[u8], content_type: ContentType) -> Result<Vec<u8>, ErrorArrayItem> {
+    let plain: Vec<u8> = build_envelope(ENVELOPE_MAGIC, data, content_type)?;
+    encrypt_data(&plain).await.uf_unwrap()
+}
+
+/// Decrypts an envelope created by `encrypt_tagged` returning its content type and payload
+pub async fn decrypt_tagged(data: &[u8]) -> Result<(ContentType, Vec<u8>), ErrorArrayItem> {
+    check_size(data.len(), MAX_ENVELOPE_CIPHERTEXT)?;
+    let plain: Vec<u8> = decrypt_data(data).await.uf_unwrap()?;
+    parse_envelope(ENVELOPE_MAGIC, &plain)
+}
+
+/// How `encrypt_with_mode` protects data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub enum EncryptionMode {
+    /// Encrypted through recs
+    #[default]
+    Recs,
+    /// Stored as plaintext. Only for benchmarking and trusted single host setups
+    None,
+}
+
+impl fmt::Display for EncryptionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionMode::Recs => write!(f, "Recs"),
+            EncryptionMode::None => write!(f, "None (plaintext)"),
+        }
+    }
+}
+
+/// Wraps `data` in an envelope using the given mode.
+///
+/// `EncryptionMode::None` writes the envelope unencrypted under its own
+/// magic (`AHP`), so a plaintext blob can never be taken for ciphertext and
+/// `decrypt_with_mode` refuses it unless plaintext was asked for.
+pub async fn encrypt_with_mode(
+    data: &[u8],
+    content_type: ContentType,
+    mode: EncryptionMode,
+) -> Result<Vec<u8>, ErrorArrayItem> {
+    match mode {
+        EncryptionMode::Recs => encrypt_tagged(data, content_type).await,
+        EncryptionMode::None => {
+            log!(
+                LogLevel::Warn,
+                "Encryption disabled, writing {} as plaintext",
+                content_type
+            );
+            build_envelope(PLAINTEXT_MAGIC, data, content_type)
+        }
+    }
+}
+
+/// Opens an envelope from `encrypt_with_mode`. Encrypted envelopes are
+/// always accepted, plaintext ones only when `mode` is `EncryptionMode::None`.
+pub async fn decrypt_with_mode(
+    data: &[u8],
+    mode: EncryptionMode,
+) -> Result<(ContentType, Vec<u8>), ErrorArrayItem> {
+    if !data.starts_with(PLAINTEXT_MAGIC) {
+        return decrypt_tagged(data).await;
+    }
+
+    if mode != EncryptionMode::None {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidType,
+            "Refusing to load a plaintext envelope while encryption is enabled".to_owned(),
+        ));
+    }
+
+    log!(LogLevel::Warn, "Encryption disabled, reading a plaintext envelope");
+    parse_envelope(PLAINTEXT_MAGIC, data)
+}
+
+fn build_envelope(
+    magic: &[u8; 3],
+    data: &[u8],
+    content_type: ContentType,
+) -> Result<Vec<u8>, ErrorArrayItem> {
+    check_size(data.len(), MAX_ENVELOPE_PAYLOAD)?;
+
+    let mut plain: Vec<u8> = Vec::with_capacity(ENVELOPE_HEADER_LEN + data.len());
+    plain.extend_from_slice(magic);
+    plain.push(ENVELOPE_VERSION);
+    plain.push(content_type.to_tag());
+    plain.extend_from_slice(data);
+    Ok(plain)
+}
+
+fn check_size(len: usize, limit: usize) -> Result<(), ErrorArrayItem> {
+    if len > limit {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidBufferFit,
+            format!(
+                "Envelope of {} bytes exceeds the limit of {} bytes",
+                len, limit
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn parse_envelope(magic: &[u8; 3], plain: &[u8]) -> Result<(ContentType, Vec<u8>), ErrorArrayItem> {
+    check_size(plain.len(), MAX_ENVELOPE_PAYLOAD + ENVELOPE_HEADER_LEN)?;
+    if plain.len() < ENVELOPE_HEADER_LEN || &plain[..3] != magic {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidType,
+            "Data is not a tagged envelope".to_owned(),
+        ));
+    }
+
+    if plain[3] != ENVELOPE_VERSION {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidMapVersion,
+            format!("Unsupported envelope version: {}", plain[3] as char),
+        ));
+    }
+
+    let content_type: ContentType = ContentType::from_tag(plain[4]).ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::InvalidType,
+            format!("Unknown envelope content type: {}", plain[4] as char),
+        )
+    })?;
+
+    Ok((content_type, plain[ENVELOPE_HEADER_LEN..].to_vec()))
+}
+
+/// Decrypts an envelope and rejects it early if it holds a different kind of artifact
+pub async fn decrypt_expecting(data: &[u8], expected: ContentType) -> Result<Vec<u8>, ErrorArrayItem> {
+    let (content_type, payload) = decrypt_tagged(data).await?;
+    check_content_type(content_type, expected)?;
+    Ok(payload)
+}
+
+/// Like `decrypt_expecting`, but also opens blobs written with `encrypt_text`
+/// before envelopes existed. Those have no header and are returned as they
+/// decrypt, so only tagged blobs get the content type check.
+pub async fn decrypt_expecting_or_legacy(
+    data: &[u8],
+    expected: ContentType,
+) -> Result<Vec<u8>, ErrorArrayItem> {
+    check_size(data.len(), MAX_ENVELOPE_CIPHERTEXT)?;
+    let plain: Vec<u8> = decrypt_data(data).await.uf_unwrap()?;
+
+    if !plain.starts_with(ENVELOPE_MAGIC) {
+        log!(LogLevel::Debug, "Loaded an untagged {} blob", expected);
+        return Ok(plain);
+    }
+
+    let (content_type, payload) = parse_envelope(ENVELOPE_MAGIC, &plain)?;
+    check_content_type(content_type, expected)?;
+    Ok(payload)
+}
+
+/// Opens a blob from `encrypt_with_mode` that should hold `expected`.
+/// Plaintext envelopes are only accepted when `mode` is `EncryptionMode::None`,
+/// blobs from before envelopes existed are accepted like `decrypt_expecting_or_legacy`.
+pub async fn decrypt_expecting_with_mode(
+    data: &[u8],
+    expected: ContentType,
+    mode: EncryptionMode,
+) -> Result<Vec<u8>, ErrorArrayItem> {
+    if !data.starts_with(PLAINTEXT_MAGIC) {
+        return decrypt_expecting_or_legacy(data, expected).await;
+    }
+
+    let (content_type, payload) = decrypt_with_mode(data, mode).await?;
+    check_content_type(content_type, expected)?;
+    Ok(payload)
+}
+
+fn check_content_type(found: ContentType, expected: ContentType) -> Result<(), ErrorArrayItem> {
+    if found != expected {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidType,
+            format!(
+                "Content type mismatch: expected {} but found {}",
+                expected, found
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Outcome of `rotate_encryption`, one entry per path
+#[derive(Debug, Default)]
+pub struct RotationReport {
+    /// Files now encrypted with the new scheme
+    pub rotated: Vec<PathType>,
+    /// Files left untouched and why
+    pub failed: Vec<(PathType, ErrorArrayItem)>,
+}
+
+impl RotationReport {
+    pub fn all_rotated(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for RotationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rotated, {} failed", self.rotated.len(), self.failed.len())
+    }
+}
+
+/// Re-encrypts every file in `paths`, decrypting with the old scheme and
+/// encrypting with the new one.
+///
+/// Each file is rewritten through a temporary file and a rename, so a file
+/// is either fully on the new scheme or untouched. The file keeps its mode
+/// and the directory is synced after the rename. A failure on one file is
+/// recorded in the report and the rest are still rotated.
+pub async fn rotate_encryption<D, E>(
+    paths: &[PathType],
+    decrypt: D,
+    encrypt: E,
+) -> Result<RotationReport, ErrorArrayItem>
+where
+    D: AsyncFn(&[u8]) -> Result<Vec<u8>, ErrorArrayItem>,
+    E: AsyncFn(&[u8]) -> Result<Vec<u8>, ErrorArrayItem>,
+{
+    let mut report: RotationReport = RotationReport::default();
+
+    for path in paths {
+        match rotate_file(path, &decrypt, &encrypt).await {
+            Ok(()) => report.rotated.push(path.clone()),
+            Err(err) => {
+                log!(LogLevel::Error, "Failed to rotate encryption of {}: {}", path, err);
+                report.failed.push((path.clone(), err));
+            }
+        }
+    }
+
+    log!(LogLevel::Info, "Encryption rotation finished: {}", report);
+    Ok(report)
+}
+
+async fn rotate_file<D, E>(path: &PathType, decrypt: &D, encrypt: &E) -> Result<(), ErrorArrayItem>
+where
+    D: AsyncFn(&[u8]) -> Result<Vec<u8>, ErrorArrayItem>,
+    E: AsyncFn(&[u8]) -> Result<Vec<u8>, ErrorArrayItem>,
+{
+    let old_data: Vec<u8> = tokio::fs::read(path).await?;
+    let mode: u32 = tokio::fs::metadata(path).await?.permissions().mode() & 0o7777;
+    let plain: Vec<u8> = decrypt(&old_data).await?;
+    let new_data: Vec<u8> = encrypt(&plain).await?;
+
+    let temp_path: PathType = PathType::Content(format!("{}.rotate.tmp", path));
+    let result: Result<(), ErrorArrayItem> = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&temp_path)
+            .await?;
+        file.set_permissions(fs::Permissions::from_mode(mode)).await?;
+        file.write_all(&new_data).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&temp_path, path).await?;
+        Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return result;
+    }
+
+    // Persist the rename itself. The file is rotated either way, so a failed
+    // sync only means the rename might not survive a crash
+    let parent: PathBuf = match path.to_path_buf().parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let synced: std::io::Result<()> = async { tokio::fs::File::open(&parent).await?.sync_all().await }.await;
+    if let Err(err) = synced {
+        log!(
+            LogLevel::Warn,
+            "Rotated {} but failed to sync {}: {}",
+            path,
+            parent.display(),
+            err
+        );
+    }
+
+    Ok(())
 }
 
 async fn execution_locked() -> bool {
@@ -177,7 +541,7 @@ async fn initialize_locker() -> Result<(), ErrorArrayItem> {
             if !cleaning_loop_initialized.load(Ordering::Relaxed) {
                 tokio::spawn(clean_loop());
             }
-            return Ok(());
+            Ok(())
         }
         false => {
             initialize(true).await.uf_unwrap()?;
@@ -204,3 +568,121 @@ async fn initialize_locker() -> Result<(), ErrorArrayItem> {
 //     code()
 //
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn xor(data: &[u8], key: u8) -> Vec<u8> {
+        data.iter().map(|byte| byte ^ key).collect()
+    }
+
+    #[tokio::test]
+    async fn rotates_files_to_new_key() {
+        let dir = TempDir::new().unwrap();
+        let first = PathType::PathBuf(dir.path().join("first"));
+        let second = PathType::PathBuf(dir.path().join("second"));
+        fs::write(&first, xor(b"alpha", 1)).unwrap();
+        fs::write(&second, xor(b"beta", 1)).unwrap();
+        fs::set_permissions(&first, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let report = rotate_encryption(
+            &[first.clone(), second.clone()],
+            async |data: &[u8]| Ok(xor(data, 1)),
+            async |data: &[u8]| Ok(xor(data, 7)),
+        )
+        .await
+        .unwrap();
+
+        assert!(report.all_rotated());
+        assert_eq!(report.rotated.len(), 2);
+        assert_eq!(xor(&fs::read(&first).unwrap(), 7), b"alpha");
+        assert_eq!(xor(&fs::read(&second).unwrap(), 7), b"beta");
+        assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o7777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn failed_file_is_left_untouched() {
+        let dir = TempDir::new().unwrap();
+        let good = PathType::PathBuf(dir.path().join("good"));
+        let missing = PathType::PathBuf(dir.path().join("missing"));
+        fs::write(&good, b"data").unwrap();
+
+        let report = rotate_encryption(
+            &[good.clone(), missing.clone()],
+            async |_: &[u8]| Err(ErrorArrayItem::new(Errors::GeneralError, "wrong key")),
+            async |data: &[u8]| Ok(data.to_vec()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.failed.len(), 2);
+        assert_eq!(fs::read(&good).unwrap(), b"data");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn rotates_with_async_crate_encryption() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("state"));
+        fs::write(&path, b"plain").unwrap();
+
+        let report = rotate_encryption(
+            std::slice::from_ref(&path),
+            async |data: &[u8]| Ok(data.to_vec()),
+            async |data: &[u8]| {
+                let text: String = String::from_utf8(data.to_vec())?;
+                Ok(encrypt_text(Stringy::from(text)).await?.as_bytes().to_vec())
+            },
+        )
+        .await
+        .unwrap();
+        assert!(report.all_rotated());
+
+        let stored: String = fs::read_to_string(&path).unwrap();
+        let decrypted: Stringy = decrypt_text(Stringy::from(stored)).await.unwrap();
+        assert_eq!(decrypted.to_string(), "plain");
+    }
+
+    #[tokio::test]
+    async fn tagged_round_trip_keeps_content_type() {
+        let blob = encrypt_tagged(b"payload", ContentType::GitCredentials).await.unwrap();
+
+        let (content_type, payload) = decrypt_tagged(&blob).await.unwrap();
+        assert_eq!(content_type, ContentType::GitCredentials);
+        assert_eq!(payload, b"payload");
+
+        let err = decrypt_expecting(&blob, ContentType::AppState).await.unwrap_err();
+        assert_eq!(err.err_type, Errors::InvalidType);
+        assert!(err
+            .err_mesg
+            .to_string()
+            .contains("expected AppState but found GitCredentials"));
+    }
+
+    #[tokio::test]
+    async fn legacy_blob_is_accepted_only_where_asked() {
+        let legacy = encrypt_text(Stringy::from("old = true")).await.unwrap();
+
+        let plain = decrypt_expecting_or_legacy(legacy.as_bytes(), ContentType::AppState)
+            .await
+            .unwrap();
+        assert_eq!(plain, b"old = true");
+        assert!(decrypt_expecting(legacy.as_bytes(), ContentType::AppState).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_blobs_are_refused_before_decrypting() {
+        let huge: Vec<u8> = vec![b'0'; MAX_ENVELOPE_CIPHERTEXT + 1];
+
+        let err = decrypt_tagged(&huge).await.unwrap_err();
+        assert_eq!(err.err_type, Errors::InvalidBufferFit);
+        let err = decrypt_expecting_or_legacy(&huge, ContentType::AppState).await.unwrap_err();
+        assert_eq!(err.err_type, Errors::InvalidBufferFit);
+
+        let too_long: Vec<u8> = vec![0u8; MAX_ENVELOPE_PAYLOAD + 1];
+        assert!(encrypt_tagged(&too_long, ContentType::Generic).await.is_err());
+    }
+}
+
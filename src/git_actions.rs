@@ -1,24 +1,38 @@
+use std::collections::VecDeque;
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::future::Future;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
 use std::pin::Pin;
-use std::process::Output;
+use std::process::{Output, Stdio};
+use std::time::Duration;
 
 use colored::Colorize;
+use nix::sys::statvfs::statvfs;
+use walkdir::WalkDir;
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::Semaphore;
 
 use dusa_collection_utils::{
     errors::{ErrorArrayItem, Errors},
     functions::{create_hash, truncate},
+    log,
+    log::LogLevel,
     stringy::Stringy,
     types::PathType,
 };
 
-use crate::encryption::{decrypt_text, encrypt_text};
+use crate::config::GitConfig;
+use crate::encryption::{decrypt_expecting_or_legacy, encrypt_tagged, ContentType};
+use crate::state_persistence::PersistenceError;
 
 pub const ARTISANCF: &str = "/opt/artisan/artisan.cf";
+pub const PROJECT_BASE_DIR: &str = "/var/www/ais";
+pub const DEFAULT_PROJECT_ID_LENGTH: usize = 8;
 
 /// Represents the Git server to interact with.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone)]
@@ -28,10 +42,45 @@ pub enum GitServer {
     Custom(String), // Custom server URL
 }
 
+impl GitServer {
+    /// Returns the base URL of the server without a trailing slash.
+    /// Custom servers keep any port given, e.g. `https://git.example.com:8443`.
+    pub fn base_url(&self) -> &str {
+        match self {
+            GitServer::GitHub => "https://github.com",
+            GitServer::GitLab => "https://gitlab.com",
+            GitServer::Custom(url) => url.trim_end_matches('/'),
+        }
+    }
+
+    /// Builds the clone URL for a repository on this server.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The owner of the repository. Self-hosted GitLab or Gitea
+    ///   instances may use nested groups, e.g. `group/subgroup`, which are kept as is.
+    /// * `repo` - The name of the repository, with or without a `.git` suffix.
+    ///
+    /// # Returns
+    ///
+    /// The full `.git` URL of the repository.
+    pub fn repo_url(&self, owner: &str, repo: &str) -> String {
+        let owner: String = owner
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<&str>>()
+            .join("/");
+        let repo: &str = repo.trim_matches('/').trim_end_matches(".git");
+
+        format!("{}/{}/{}.git", self.base_url(), owner, repo)
+    }
+}
+
 /// Represents Git authentication information for a repository.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
 pub struct GitAuth {
-    /// The username or owner of the repository.
+    /// The username or owner of the repository. May contain nested group
+    /// segments (`group/subgroup`) for self-hosted servers.
     pub user: Stringy,
     /// The name of the repository.
     pub repo: Stringy,
@@ -78,6 +127,9 @@ pub enum GitAction {
     CheckRemoteAhead {
         directory: PathType,
     },
+    AheadBehind {
+        directory: PathType,
+    },
     Switch {
         branch: Stringy,
         destination: PathType,
@@ -101,8 +153,69 @@ pub enum GitAction {
     },
 }
 
+/// How far the local branch has diverged from its upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AheadBehind {
+    /// Commits on the local branch that the upstream doesn't have.
+    pub ahead: u32,
+    /// Commits on the upstream that the local branch doesn't have.
+    pub behind: u32,
+}
+
+impl AheadBehind {
+    /// Parses the output of `git rev-list --left-right --count @{u}...@`,
+    /// which prints the upstream-only count followed by the local-only count.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if the output doesn't contain two counts.
+    pub fn parse(output: &str) -> Result<Self, ErrorArrayItem> {
+        let mut counts = output.split_whitespace().map(|part| part.parse::<u32>());
+
+        match (counts.next(), counts.next(), counts.next()) {
+            (Some(Ok(behind)), Some(Ok(ahead)), None) => Ok(Self { ahead, behind }),
+            _ => Err(ErrorArrayItem::new(
+                Errors::Git,
+                format!("Unexpected rev-list output: {}", output.trim()),
+            )),
+        }
+    }
+
+    /// Parses the `Output` returned by `GitAction::AheadBehind`.
+    pub fn from_output(output: &Output) -> Result<Self, ErrorArrayItem> {
+        Self::parse(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// The local branch can be fast forwarded to the upstream.
+    pub fn can_fast_forward(&self) -> bool {
+        self.ahead == 0 && self.behind > 0
+    }
+
+    /// Both sides have commits the other doesn't.
+    pub fn diverged(&self) -> bool {
+        self.ahead > 0 && self.behind > 0
+    }
+}
+
+/// Which branches `set_track` set up and which it couldn't.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetTrackReport {
+    /// Branches now tracking their remote.
+    pub succeeded: Vec<Stringy>,
+    /// Branches that failed, with git's error.
+    pub failed: Vec<(Stringy, String)>,
+}
+
+impl SetTrackReport {
+    /// Every branch was tracked.
+    pub fn all_succeeded(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
 impl GitCredentials {
     /// Creates a new instance of `GitCredentials` by reading and decrypting the credentials file.
+    /// Same as `load_async`.
     ///
     /// # Returns
     ///
@@ -112,28 +225,29 @@ impl GitCredentials {
     ///
     /// Returns an `ErrorArrayItem` if reading, decrypting, or deserializing fails.
     pub async fn new(file: Option<&PathType>) -> Result<Self, ErrorArrayItem> {
-        match file {
-            Some(file) => {
-                if file.exists() {
-                    let encrypted_credentials = Self::read_file(file)?;
-                    let decrypted_string =
-                        decrypt_text(encrypted_credentials).await?.replace('\n', "");
-                    let data: GitCredentials = serde_json::from_str(&decrypted_string)?;
-                    Ok(data)
-                } else {
-                    Err(ErrorArrayItem::new(
-                        Errors::InvalidFile,
-                        "No such file or directory".to_owned(),
-                    ))
-                }
-            }
-            None => {
-                let encrypted_credentials = Self::read_file(&PathType::Str(ARTISANCF.into()))?;
-                let decrypted_string = decrypt_text(encrypted_credentials).await?.replace('\n', "");
-                let data: GitCredentials = serde_json::from_str(&decrypted_string)?;
-                Ok(data)
-            }
-        }
+        Self::load_async(file).await
+    }
+
+    /// Loads the credentials from `file`, or the default credentials file,
+    /// reading it with `tokio::fs` so deploy tasks don't stall the runtime on disk I/O.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if the file is missing, or reading,
+    /// decrypting, or deserializing fails.
+    pub async fn load_async(file: Option<&PathType>) -> Result<Self, ErrorArrayItem> {
+        let path: PathType = match file {
+            Some(file) => file.clone(),
+            None => PathType::Str(ARTISANCF.into()),
+        };
+
+        Self::try_load(&path).await.map_err(|err| match err {
+            PersistenceError::Io(err) => ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Unable to read {}: {}", path, err),
+            ),
+            err => ErrorArrayItem::from(err),
+        })
     }
 
     /// Creates a new vector of `GitAuth` items by loading the credentials.
@@ -157,6 +271,10 @@ impl GitCredentials {
 
     /// Saves the `GitCredentials` to the specified file path by serializing and encrypting the data.
     ///
+    /// The data is written to a temporary file next to `path`, synced, and then
+    /// renamed over the original so a crash mid-write never leaves a corrupted
+    /// credentials file behind. The previous file is kept as `<path>.bak`.
+    ///
     /// # Arguments
     ///
     /// * `file_path` - The path to the file where credentials will be saved.
@@ -165,53 +283,153 @@ impl GitCredentials {
     ///
     /// Returns an `ErrorArrayItem` if serialization, encryption, or file writing fails.
     pub async fn save(&self, path: &PathType) -> Result<(), ErrorArrayItem> {
-        // if the array is empty we delete and re-create the empty file
-        if self.clone().to_vec().len() == 0 {
-            path.delete()?;
-        }
+        let encrypted_data = self.encode().await?;
+        Self::write_atomic(path, &encrypted_data)
+    }
 
-        // Serialize GitCredentials to JSON
-        let json_data = serde_json::to_string(self).map_err(|e| {
-            ErrorArrayItem::new(Errors::GeneralError, format!("Serialization error: {}", e))
-        })?;
+    /// Saves like `save`, reporting which step failed as a `PersistenceError`.
+    pub async fn try_save(&self, path: &PathType) -> Result<(), PersistenceError> {
+        let encrypted_data = self.encode().await?;
+        Self::write_atomic(path, &encrypted_data)
+            .map_err(|e| PersistenceError::Io(io::Error::other(e.err_mesg.to_string())))
+    }
+
+    /// Loads like `new`, reporting which step failed as a `PersistenceError`.
+    pub async fn try_load(path: &PathType) -> Result<Self, PersistenceError> {
+        let encrypted_credentials: String = tokio::fs::read_to_string(path).await?;
+        Self::decode(&encrypted_credentials).await
+    }
+
+    /// Serializes and encrypts the credentials into a `GitCredentials` envelope
+    async fn encode(&self) -> Result<String, PersistenceError> {
+        let json_data =
+            serde_json::to_string(self).map_err(|e| PersistenceError::Serde(e.to_string()))?;
+
+        let encrypted_data: Vec<u8> = encrypt_tagged(json_data.as_bytes(), ContentType::GitCredentials)
+            .await
+            .map_err(|e| PersistenceError::Encrypt(e.err_mesg.to_string()))?;
+
+        String::from_utf8(encrypted_data).map_err(|e| PersistenceError::Encrypt(e.to_string()))
+    }
 
-        // Encrypt the JSON data
-        let encrypted_data = encrypt_text(Stringy::from(&json_data)).await?;
+    /// Decrypts and parses the contents of a credentials file. Files written
+    /// before envelopes existed are still accepted.
+    async fn decode(encrypted_credentials: &str) -> Result<Self, PersistenceError> {
+        let encrypted_credentials: String = encrypted_credentials.replace('\n', "");
+        let decrypted: Vec<u8> =
+            decrypt_expecting_or_legacy(encrypted_credentials.as_bytes(), ContentType::GitCredentials)
+                .await
+                .map_err(|e| PersistenceError::Decrypt(e.err_mesg.to_string()))?;
+
+        let decrypted_string: String = String::from_utf8(decrypted)
+            .map_err(|e| PersistenceError::Decrypt(e.to_string()))?
+            .replace('\n', "");
+
+        serde_json::from_str(&decrypted_string).map_err(|e| PersistenceError::Serde(e.to_string()))
+    }
+
+    /// Writes `encrypted_data` to a temporary file next to `path` and renames it into place,
+    /// keeping the previous file as `<path>.bak`.
+    fn write_atomic(path: &PathType, encrypted_data: &str) -> Result<(), ErrorArrayItem> {
+        let temp_path: PathType = PathType::Content(format!("{}.tmp", path));
+        let backup_path: PathType = PathType::Content(format!("{}.bak", path));
+
+        // The credentials keep the mode of the file they replace, or 0600 when
+        // there is none, so the rename never widens who can read them
+        let mode: u32 = match fs::metadata(path) {
+            Ok(metadata) => metadata.permissions().mode() & 0o7777,
+            Err(_) => 0o600,
+        };
 
-        // Write the encrypted data to the file
+        // Write the encrypted data to the temporary file
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true) // Ensure the file is truncated to avoid leftover data
-            .open(path)
+            .mode(mode)
+            .open(&temp_path)
             .map_err(|e| {
                 ErrorArrayItem::new(
                     Errors::InvalidFile,
                     format!(
                         "Unable to create or open the file: {:?}, error: {}",
-                        path, e
+                        temp_path, e
                     ),
                 )
             })?;
 
+        // A leftover temp file keeps its old mode, so set it explicitly
+        file.set_permissions(fs::Permissions::from_mode(mode)).map_err(|e| {
+            let _ = temp_path.delete();
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Unable to set permissions on: {:?}, error: {}", temp_path, e),
+            )
+        })?;
+
         file.write_all(encrypted_data.as_bytes()).map_err(|e| {
+            let _ = temp_path.delete();
             ErrorArrayItem::new(
                 Errors::ReadingFile,
-                format!("Unable to write to the file: {:?}, error: {}", path, e),
+                format!("Unable to write to the file: {:?}, error: {}", temp_path, e),
             )
         })?;
 
-        // Ensure data is flushed to disk
+        // Ensure data is flushed to disk before it replaces the original
         file.sync_all().map_err(|e| {
+            let _ = temp_path.delete();
             ErrorArrayItem::new(
                 Errors::ReadingFile,
-                format!("Unable to sync data to the file: {:?}, error: {}", path, e),
+                format!("Unable to sync data to the file: {:?}, error: {}", temp_path, e),
             )
         })?;
+        drop(file);
+
+        // Keep the last good copy around
+        if path.exists() {
+            fs::copy(path, &backup_path).map_err(|e| {
+                ErrorArrayItem::new(
+                    Errors::CreatingFile,
+                    format!("Unable to back up the file: {:?}, error: {}", path, e),
+                )
+            })?;
+        }
+
+        fs::rename(&temp_path, path).map_err(|e| {
+            let _ = temp_path.delete();
+            ErrorArrayItem::new(
+                Errors::CreatingFile,
+                format!("Unable to replace the file: {:?}, error: {}", path, e),
+            )
+        })?;
+
+        // Persist the rename itself
+        if let Some(parent) = path.to_path_buf().parent() {
+            let parent: &Path = match parent.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => parent,
+            };
+            File::open(parent).and_then(|dir| dir.sync_all()).map_err(|e| {
+                ErrorArrayItem::new(
+                    Errors::InputOutput,
+                    format!("Unable to sync the directory of: {:?}, error: {}", path, e),
+                )
+            })?;
+        }
 
         Ok(())
     }
 
+    /// Loads the `.bak` copy written by the last successful `save`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if there is no backup or it can't be decrypted.
+    pub async fn load_backup(path: &PathType) -> Result<Self, ErrorArrayItem> {
+        let backup_path: PathType = PathType::Content(format!("{}.bak", path));
+        Self::new(Some(&backup_path)).await
+    }
+
     /// Reads the contents of a file and returns it as a `Stringy`, removing any newline characters.
     ///
     /// # Arguments
@@ -229,7 +447,7 @@ impl GitCredentials {
         let mut file = File::open(file_path)?;
         let mut file_contents = String::new();
         file.read_to_string(&mut file_contents)?;
-        Ok(Stringy::from(&file_contents.replace('\n', "")))
+        Ok(Stringy::from(file_contents.replace('\n', "")))
     }
 
     /// Adds a new `GitAuth` item to the credentials.
@@ -241,25 +459,54 @@ impl GitCredentials {
         self.auth_items.push(auth);
     }
 
-    /// Bootstraps Git credentials by attempting to load existing credentials or creating a new default set.
+    /// Returns true when there are no credentials to clone with.
+    pub fn is_empty(&self) -> bool {
+        self.auth_items.is_empty()
+    }
+
+    /// Bootstraps Git credentials from the default credentials file.
+    ///
+    /// See `bootstrap_git_credentials_from` for how a missing or corrupt file is handled.
+    pub async fn bootstrap_git_credentials() -> Result<GitCredentials, ErrorArrayItem> {
+        Self::bootstrap_git_credentials_from(&PathType::Str(ARTISANCF.into())).await
+    }
+
+    /// Bootstraps Git credentials from `path`.
     ///
     /// # Returns
     ///
-    /// Returns a `GitCredentials` instance.
+    /// An empty set when the file doesn't exist yet, as on a first run,
+    /// otherwise the credentials stored in it.
     ///
     /// # Errors
     ///
-    /// Can't panic.
-    pub async fn bootstrap_git_credentials() -> Result<GitCredentials, ErrorArrayItem> {
-        match GitCredentials::new(None).await {
-            Ok(creds) => Ok(creds),
-            Err(_) => {
-                let default_creds = GitCredentials {
-                    auth_items: Vec::new(),
-                };
-                Ok(default_creds)
-            }
+    /// Returns an `ErrorArrayItem` if the file exists but can't be read,
+    /// decrypted or parsed, so a corrupt file isn't mistaken for having no
+    /// credentials.
+    pub async fn bootstrap_git_credentials_from(path: &PathType) -> Result<GitCredentials, ErrorArrayItem> {
+        if !path.exists() {
+            log!(
+                LogLevel::Warn,
+                "No git credentials file at {}, starting with zero credentials",
+                path
+            );
+            return Ok(GitCredentials {
+                auth_items: Vec::new(),
+            });
+        }
+
+        let credentials: GitCredentials = Self::try_load(path).await.map_err(|err| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Git credentials file {} is unreadable or corrupt: {}", path, err),
+            )
+        })?;
+
+        if credentials.is_empty() {
+            log!(LogLevel::Warn, "Git credentials file {} holds zero credentials", path);
         }
+
+        Ok(credentials)
     }
 
     /// Deletes the value at the 'index' in the git credentials array
@@ -289,20 +536,16 @@ impl GitAuth {
     ///
     /// A full Git remote URL string.
     pub fn assemble_remote_url(&self) -> String {
-        let base_url = match &self.server {
-            GitServer::GitHub => "https://github.com",
-            GitServer::GitLab => "https://gitlab.com",
-            GitServer::Custom(url) => url.trim_end_matches('/'),
-        };
+        let url = self.server.repo_url(&self.user, &self.repo);
 
         // Construct the URL, adding token if available
         if let Some(token) = &self.token {
-            format!(
-                "https://{}@{}/{}/{}.git",
-                token, base_url, self.user, self.repo
-            )
+            match url.split_once("://") {
+                Some((scheme, rest)) => format!("{}://{}@{}", scheme, token, rest),
+                None => format!("https://{}@{}", token, url),
+            }
         } else {
-            format!("{}/{}/{}.git", base_url, self.user, self.repo)
+            url
         }
     }
 
@@ -316,6 +559,63 @@ impl GitAuth {
 }
 
 impl GitAction {
+    /// Executes the action, streaming Git's output to `on_line` line by line.
+    ///
+    /// Clones and fetches are streamed as they run so a verbose transfer of
+    /// a large repo isn't held in memory. Other actions are executed normally
+    /// and their output is passed to `on_line` afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if the action fails.
+    pub async fn execute_streaming<F>(&self, mut on_line: F) -> Result<(), ErrorArrayItem>
+    where
+        F: FnMut(GitStream, &str),
+    {
+        match self {
+            GitAction::Clone {
+                repo_name,
+                repo_owner,
+                destination,
+                repo_branch,
+                server,
+            } => {
+                check_git_installed().await?;
+                let url = server.repo_url(repo_owner, repo_name);
+                execute_git_command_streaming(
+                    &["clone", "-b", repo_branch, &url, &destination.to_string()],
+                    on_line,
+                )
+                .await
+            }
+            GitAction::Fetch { destination } => {
+                if !destination.exists() {
+                    return Err(ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        "Repository path not found".to_string(),
+                    ));
+                }
+
+                execute_git_command_streaming(
+                    &["-C", &destination.to_string(), "fetch", "--all"],
+                    on_line,
+                )
+                .await
+            }
+            _ => {
+                if let Some(output) = self.execute().await? {
+                    for line in String::from_utf8_lossy(&output.stdout).lines() {
+                        on_line(GitStream::Stdout, line);
+                    }
+                    for line in String::from_utf8_lossy(&output.stderr).lines() {
+                        on_line(GitStream::Stderr, line);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
     /// Executes the specified Git action asynchronously.
     ///
     /// # Returns
@@ -339,22 +639,7 @@ impl GitAction {
                     repo_branch,
                     server,
                 } => {
-                    let url = match server {
-                        GitServer::GitHub => {
-                            format!("https://github.com/{}/{}.git", repo_owner, repo_name)
-                        }
-                        GitServer::GitLab => {
-                            format!("https://gitlab.com/{}/{}.git", repo_owner, repo_name)
-                        }
-                        GitServer::Custom(base_url) => {
-                            format!(
-                                "{}/{}/{}.git",
-                                base_url.trim_end_matches('/'),
-                                repo_owner,
-                                repo_name
-                            )
-                        }
-                    };
+                    let url = server.repo_url(repo_owner, repo_name);
 
                     execute_git_command(&[
                         "clone",
@@ -444,6 +729,26 @@ impl GitAction {
                         Ok(None)
                     }
                 }
+                GitAction::AheadBehind { directory } => {
+                    if directory.exists() {
+                        execute_git_command(&["-C", &directory.to_string(), "fetch"]).await?;
+                        execute_git_command(&[
+                            "-C",
+                            &directory.to_string(),
+                            "rev-list",
+                            "--left-right",
+                            "--count",
+                            "@{u}...@",
+                        ])
+                        .await
+                        .map(Some)
+                    } else {
+                        Err(ErrorArrayItem::new(
+                            Errors::InvalidFile,
+                            "Repository path not found".to_string(),
+                        ))
+                    }
+                }
                 GitAction::Fetch { destination } => {
                     if destination.exists() {
                         execute_git_command(&["-C", &destination.to_string(), "fetch", "--all"])
@@ -481,49 +786,26 @@ impl GitAction {
                 .await
                 .map(Some),
                 GitAction::SetTrack { directory } => {
-                    if directory.exists() {
-                        execute_git_command(&["-C", &directory.to_string(), "fetch"]).await?;
-                        let branch_output = Self::Branch {
-                            directory: directory.clone(),
-                        }
-                        .execute()
-                        .await?;
-
-                        if let Some(output) = branch_output {
-                            let output_str = String::from_utf8_lossy(&output.stdout);
-                            let branches: Vec<&str> = output_str
-                                .lines()
-                                .filter(|line| !line.contains("->"))
-                                .map(|line| line.trim())
-                                .collect();
-
-                            for remote in branches {
-                                let clean_remote = remote.replace("origin/", "");
-                                if !clean_remote.is_empty() {
-                                    execute_git_command(&[
-                                        "-C",
-                                        &directory.to_string(),
-                                        "branch",
-                                        "--track",
-                                        &clean_remote,
-                                        remote,
-                                    ])
-                                    .await?;
-                                }
-                            }
-                            Ok(None)
-                        } else {
-                            Err(ErrorArrayItem::new(
-                                Errors::Git,
-                                "Invalid branch data from the current repository".to_string(),
-                            ))
-                        }
-                    } else {
-                        Err(ErrorArrayItem::new(
-                            Errors::InvalidFile,
-                            "Repository path not found".to_string(),
-                        ))
+                    let report: SetTrackReport = set_track(directory).await?;
+                    if report.all_succeeded() {
+                        return Ok(None);
                     }
+
+                    let failures: Vec<String> = report
+                        .failed
+                        .iter()
+                        .map(|(branch, err)| format!("{}: {}", branch, err.trim()))
+                        .collect();
+                    Err(ErrorArrayItem::new(
+                        Errors::Git,
+                        format!(
+                            "Failed to track {} of {} branches in {}: {}",
+                            report.failed.len(),
+                            report.failed.len() + report.succeeded.len(),
+                            directory,
+                            failures.join("; ")
+                        ),
+                    ))
                 }
                 GitAction::Branch { directory } => {
                     if directory.exists() {
@@ -566,6 +848,293 @@ impl GitAction {
     }
 }
 
+/// Limits how many Git subprocesses run at the same time.
+///
+/// Mass redeploys can submit many actions at once, each spawning its own
+/// `git` process. Routing them through a shared scheduler smooths out the
+/// CPU and IO spikes.
+pub struct GitScheduler {
+    semaphore: Semaphore,
+}
+
+impl GitScheduler {
+    /// Creates a scheduler allowing at most `concurrency` actions to run at once.
+    /// A concurrency of `0` is treated as `1`.
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(concurrency.max(1)),
+        }
+    }
+
+    /// Waits for a free slot and executes the given action.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - The `GitAction` to execute.
+    ///
+    /// # Returns
+    ///
+    /// Returns the result of `GitAction::execute`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if the scheduler is closed or the action fails.
+    pub async fn run(&self, action: GitAction) -> Result<Option<Output>, ErrorArrayItem> {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::Git, e.to_string()))?;
+
+        action.execute().await
+    }
+
+    /// Returns the number of slots currently free.
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+}
+
+/// Free space a clone must leave behind on the destination filesystem, in MB
+pub const DEFAULT_MIN_FREE_MB: u64 = 512;
+
+/// Keeps clones from filling the disk.
+///
+/// Before a clone the destination filesystem must have `min_free_mb` free
+/// on top of the projected repo size. When `max_repo_mb` is set the size is
+/// projected with a shallow probe of the branch tip first, and since the
+/// full history can be larger the finished clone is measured and removed
+/// again if it's over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskGuard {
+    pub max_repo_mb: Option<u64>,
+    pub min_free_mb: u64,
+}
+
+impl Default for DiskGuard {
+    fn default() -> Self {
+        Self {
+            max_repo_mb: None,
+            min_free_mb: DEFAULT_MIN_FREE_MB,
+        }
+    }
+}
+
+impl DiskGuard {
+    pub fn from_config(config: &GitConfig) -> Self {
+        Self {
+            max_repo_mb: config.max_repo_mb,
+            ..Self::default()
+        }
+    }
+
+    /// Checks a clone into `destination` is allowed, given its projected size if known.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if the projected size is over `max_repo_mb`
+    /// or the filesystem doesn't have enough free space.
+    pub fn check(&self, destination: &PathType, projected_mb: Option<u64>) -> Result<(), ErrorArrayItem> {
+        if let (Some(max), Some(projected)) = (self.max_repo_mb, projected_mb) {
+            if projected > max {
+                return Err(ErrorArrayItem::new(
+                    Errors::Git,
+                    format!(
+                        "Refusing to clone into {}: projected size {} MB exceeds the {} MB limit",
+                        destination, projected, max
+                    ),
+                ));
+            }
+        }
+
+        let free: u64 = free_space_mb(destination)?;
+        let required: u64 = self.min_free_mb + projected_mb.unwrap_or(0);
+        if free < required {
+            return Err(ErrorArrayItem::new(
+                Errors::Git,
+                format!(
+                    "Refusing to clone into {}: {} MB free, {} MB required",
+                    destination, free, required
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Runs a `GitAction::Clone` with the size and free space checks before
+    /// it and the size check after it. Other actions are executed unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if a check fails or the clone itself fails.
+    pub async fn clone_guarded(&self, action: &GitAction) -> Result<Option<Output>, ErrorArrayItem> {
+        let max_mb: u64 = self.max_repo_mb.unwrap_or(u64::MAX);
+        self.clone_guarded_with(action, async |url: &str, branch: &str, destination: &PathType| {
+            probe_clone_size_mb(url, branch, destination, max_mb).await
+        })
+        .await
+    }
+
+    /// `clone_guarded` with the size projection done by `probe`, which is
+    /// given the repo url, branch and destination of the clone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ErrorArrayItem` if the probe or a check fails or the clone itself fails.
+    pub async fn clone_guarded_with<P>(
+        &self,
+        action: &GitAction,
+        probe: P,
+    ) -> Result<Option<Output>, ErrorArrayItem>
+    where
+        P: AsyncFn(&str, &str, &PathType) -> Result<u64, ErrorArrayItem>,
+    {
+        let (destination, url, branch): (&PathType, String, &Stringy) = match action {
+            GitAction::Clone {
+                repo_name,
+                repo_owner,
+                destination,
+                repo_branch,
+                server,
+            } => (destination, server.repo_url(repo_owner, repo_name), repo_branch),
+            _ => return action.execute().await,
+        };
+
+        self.check(destination, None)?;
+        if self.max_repo_mb.is_some() {
+            let projected: u64 = probe(&url, branch, destination).await?;
+            self.check(destination, Some(projected))?;
+        }
+
+        let output = action.execute().await?;
+
+        if let Some(max) = self.max_repo_mb {
+            let size: u64 = dir_size_mb(destination);
+            if size > max {
+                if let Err(err) = fs::remove_dir_all(destination) {
+                    log!(LogLevel::Error, "Failed to remove oversized clone {}: {}", destination, err);
+                }
+                return Err(ErrorArrayItem::new(
+                    Errors::Git,
+                    format!(
+                        "Clone into {} is {} MB which exceeds the {} MB limit, removed",
+                        destination, size, max
+                    ),
+                ));
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+/// How often a running clone probe is measured against its cap
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Projects the size of cloning `branch` of `url` into `destination`, in MB.
+///
+/// The branch tip is shallow cloned into a scratch directory next to
+/// `destination`, measured and removed again. History isn't fetched, so the
+/// real clone can be larger. The scratch directory is measured while the
+/// probe runs and the probe is killed as soon as it grows past `max_mb`, in
+/// which case the size reached so far is returned.
+///
+/// # Errors
+///
+/// Returns an `ErrorArrayItem` if the probe clone fails.
+pub async fn probe_clone_size_mb(
+    url: &str,
+    branch: &str,
+    destination: &PathType,
+    max_mb: u64,
+) -> Result<u64, ErrorArrayItem> {
+    check_git_installed().await?;
+    let probe: PathType = PathType::Content(format!("{}.probe", destination));
+    if probe.exists() {
+        fs::remove_dir_all(&probe)?;
+    }
+
+    let result = run_capped_probe(url, branch, &probe, max_mb).await;
+
+    if probe.exists() {
+        if let Err(err) = fs::remove_dir_all(&probe) {
+            log!(LogLevel::Warn, "Failed to remove clone probe {}: {}", probe, err);
+        }
+    }
+
+    result
+}
+
+/// Runs the shallow probe clone into `probe`, killing it once `probe` is over `max_mb`
+async fn run_capped_probe(
+    url: &str,
+    branch: &str,
+    probe: &PathType,
+    max_mb: u64,
+) -> Result<u64, ErrorArrayItem> {
+    let mut child = Command::new("git")
+        .args(["clone", "-q", "--bare", "--depth", "1", "--single-branch", "-b", branch, url])
+        .arg(probe.to_path_buf())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(ErrorArrayItem::from)?;
+
+    loop {
+        tokio::select! {
+            status = child.wait() => {
+                let status = status.map_err(ErrorArrayItem::from)?;
+                if status.success() {
+                    return Ok(dir_size_mb(probe));
+                }
+
+                let mut stderr: String = String::new();
+                if let Some(mut pipe) = child.stderr.take() {
+                    pipe.read_to_string(&mut stderr).await.map_err(ErrorArrayItem::from)?;
+                }
+                return Err(ErrorArrayItem::new(Errors::Git, stderr));
+            }
+            _ = tokio::time::sleep(PROBE_POLL_INTERVAL) => {
+                let size: u64 = dir_size_mb(probe);
+                if size > max_mb {
+                    if let Err(err) = child.kill().await {
+                        log!(LogLevel::Warn, "Failed to kill clone probe {}: {}", probe, err);
+                    }
+                    return Ok(size);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the space available to unprivileged users on the filesystem holding `path`, in MB.
+/// If `path` doesn't exist yet its nearest existing parent is checked.
+pub fn free_space_mb(path: &PathType) -> Result<u64, ErrorArrayItem> {
+    let path_buf = path.to_path_buf();
+    let existing = path_buf
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("/"));
+
+    let stats = statvfs(existing).map_err(ErrorArrayItem::from)?;
+    let bytes: u64 = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+    Ok(bytes / (1024 * 1024))
+}
+
+/// Total size of the files under `path`, in MB
+pub fn dir_size_mb(path: &PathType) -> u64 {
+    let bytes: u64 = WalkDir::new(path.to_path_buf())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum();
+    bytes / (1024 * 1024)
+}
+
 /// Checks if Git is installed on the system.
 ///
 /// # Errors
@@ -606,7 +1175,7 @@ async fn execute_git_command(args: &[&str]) -> Result<Output, ErrorArrayItem> {
         .args(args)
         .output()
         .await
-        .map_err(|e| ErrorArrayItem::from(e))?;
+        .map_err(ErrorArrayItem::from)?;
 
     if output.status.success() {
         Ok(output)
@@ -616,6 +1185,85 @@ async fn execute_git_command(args: &[&str]) -> Result<Output, ErrorArrayItem> {
     }
 }
 
+/// Which output stream a line from a streamed Git command came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitStream {
+    Stdout,
+    Stderr,
+}
+
+/// How many trailing stderr lines are kept for the error of a failed streamed command
+const STREAMED_ERROR_LINES: usize = 20;
+
+/// Executes a Git command, handing each output line to `on_line` as it's
+/// produced instead of buffering the whole output.
+///
+/// # Arguments
+///
+/// * `args` - A slice of command-line arguments to pass to Git.
+/// * `on_line` - Called with every stdout and stderr line.
+///
+/// # Errors
+///
+/// Returns an `ErrorArrayItem` if the command can't be spawned or exits
+/// unsuccessfully. The error carries the last few stderr lines.
+pub async fn execute_git_command_streaming<F>(args: &[&str], on_line: F) -> Result<(), ErrorArrayItem>
+where
+    F: FnMut(GitStream, &str),
+{
+    let mut command = Command::new("git");
+    command.args(args);
+    stream_command(command, on_line).await
+}
+
+/// Runs `command`, handing each output line to `on_line` as it's produced.
+/// Only the last `STREAMED_ERROR_LINES` stderr lines are kept, for the error.
+async fn stream_command<F>(mut command: Command, mut on_line: F) -> Result<(), ErrorArrayItem>
+where
+    F: FnMut(GitStream, &str),
+{
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(ErrorArrayItem::from)?;
+
+    let mut stdout = child.stdout.take().map(|stdout| BufReader::new(stdout).lines());
+    let mut stderr = child.stderr.take().map(|stderr| BufReader::new(stderr).lines());
+    let mut recent_errors: VecDeque<String> = VecDeque::with_capacity(STREAMED_ERROR_LINES);
+
+    while stdout.is_some() || stderr.is_some() {
+        tokio::select! {
+            line = async { stdout.as_mut().unwrap().next_line().await }, if stdout.is_some() => {
+                match line.map_err(ErrorArrayItem::from)? {
+                    Some(line) => on_line(GitStream::Stdout, &line),
+                    None => stdout = None,
+                }
+            }
+            line = async { stderr.as_mut().unwrap().next_line().await }, if stderr.is_some() => {
+                match line.map_err(ErrorArrayItem::from)? {
+                    Some(line) => {
+                        on_line(GitStream::Stderr, &line);
+                        if recent_errors.len() == STREAMED_ERROR_LINES {
+                            recent_errors.pop_front();
+                        }
+                        recent_errors.push_back(line);
+                    }
+                    None => stderr = None,
+                }
+            }
+        }
+    }
+
+    let status = child.wait().await.map_err(ErrorArrayItem::from)?;
+    if status.success() {
+        Ok(())
+    } else {
+        let stderr: Vec<String> = recent_errors.into_iter().collect();
+        Err(ErrorArrayItem::new(Errors::Git, stderr.join("\n")))
+    }
+}
+
 /// Checks if the remote repository is ahead of the local repository.
 ///
 /// # Arguments
@@ -640,6 +1288,101 @@ async fn check_remote_ahead(directory: &PathType) -> Result<bool, ErrorArrayItem
     Ok(remote_hash != local_hash)
 }
 
+/// Fetches and counts how many commits the local branch is ahead and behind its upstream.
+///
+/// # Arguments
+///
+/// * `directory` - The local repository directory to check.
+///
+/// # Returns
+///
+/// Returns an `AheadBehind` with both counts.
+///
+/// # Errors
+///
+/// Returns an `ErrorArrayItem` if the Git commands fail or the output can't be parsed.
+pub async fn check_ahead_behind(directory: &PathType) -> Result<AheadBehind, ErrorArrayItem> {
+    let output = GitAction::AheadBehind {
+        directory: directory.clone(),
+    }
+    .execute()
+    .await?;
+
+    match output {
+        Some(output) => AheadBehind::from_output(&output),
+        None => Err(ErrorArrayItem::new(
+            Errors::Git,
+            "No output from rev-list".to_string(),
+        )),
+    }
+}
+
+/// Sets up a local tracking branch for every remote branch in the repository.
+///
+/// Each branch is attempted even if an earlier one fails, so one bad branch
+/// doesn't leave the rest untracked.
+///
+/// # Errors
+///
+/// Returns an `ErrorArrayItem` if the repository is missing or the remote
+/// branches can't be listed. Failures on individual branches are reported
+/// in the `SetTrackReport` instead.
+pub async fn set_track(directory: &PathType) -> Result<SetTrackReport, ErrorArrayItem> {
+    if !directory.exists() {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidFile,
+            "Repository path not found".to_string(),
+        ));
+    }
+
+    execute_git_command(&["-C", &directory.to_string(), "fetch"]).await?;
+    let branch_output = GitAction::Branch {
+        directory: directory.clone(),
+    }
+    .execute()
+    .await?;
+
+    let output = branch_output.ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::Git,
+            "Invalid branch data from the current repository".to_string(),
+        )
+    })?;
+
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let branches: Vec<&str> = output_str
+        .lines()
+        .filter(|line| !line.contains("->"))
+        .map(|line| line.trim())
+        .collect();
+
+    let mut report = SetTrackReport::default();
+    for remote in branches {
+        let clean_remote = remote.replace("origin/", "");
+        if clean_remote.is_empty() {
+            continue;
+        }
+
+        match execute_git_command(&[
+            "-C",
+            &directory.to_string(),
+            "branch",
+            "--track",
+            &clean_remote,
+            remote,
+        ])
+        .await
+        {
+            Ok(_) => report.succeeded.push(Stringy::from(clean_remote)),
+            Err(err) => report
+                .failed
+                .push((Stringy::from(clean_remote), err.err_mesg.to_string())),
+        }
+    }
+
+    Ok(report)
+}
+
 /// Executes a Git command that returns a hash.
 ///
 /// # Arguments
@@ -678,7 +1421,30 @@ async fn execute_git_hash_command(args: &[&str]) -> Result<String, ErrorArrayIte
 ///
 /// Returns a `PathType` representing the project path.
 pub fn generate_git_project_path(auth: &GitAuth) -> PathType {
-    PathType::Content(format!("/var/www/ais/{}", generate_git_project_id(auth)))
+    generate_git_project_path_in(
+        auth,
+        &PathType::Str(PROJECT_BASE_DIR.into()),
+        DEFAULT_PROJECT_ID_LENGTH,
+    )
+}
+
+/// Generates the project path under a custom base directory.
+///
+/// # Arguments
+///
+/// * `auth` - A reference to `GitAuth` containing branch, repository, and user information.
+/// * `base_dir` - The directory projects are stored under.
+/// * `len` - The number of hash characters used for the project ID.
+///
+/// # Returns
+///
+/// Returns a `PathType` representing the project path.
+pub fn generate_git_project_path_in(auth: &GitAuth, base_dir: &PathType, len: usize) -> PathType {
+    PathType::PathBuf(
+        base_dir
+            .to_path_buf()
+            .join(generate_git_project_id_len(auth, len).to_string()),
+    )
 }
 
 /// Generates a unique project ID based on the Git authentication information.
@@ -691,10 +1457,29 @@ pub fn generate_git_project_path(auth: &GitAuth) -> PathType {
 ///
 /// Returns a `Stringy` representing the truncated hash of the project ID.
 pub fn generate_git_project_id(auth: &GitAuth) -> Stringy {
+    generate_git_project_id_len(auth, DEFAULT_PROJECT_ID_LENGTH)
+}
+
+/// Generates a project ID truncated to `len` hex characters.
+///
+/// The ID is a truncated sha256, so each character carries 4 bits. By the
+/// birthday bound the chance of any collision among `n` projects is roughly
+/// `n^2 / (2 * 16^len)`. With the default of 8 characters there's a ~1% chance
+/// of a collision at ~9,300 projects and ~50% at ~77,000. At 12 characters
+/// the 50% mark moves to ~19.7 million projects.
+///
+/// # Arguments
+///
+/// * `auth` - A reference to `GitAuth` containing branch, repository, and user information.
+/// * `len` - The number of hash characters to keep, capped at the 64 of the full hash.
+///
+/// # Returns
+///
+/// Returns a `Stringy` representing the truncated hash of the project ID.
+pub fn generate_git_project_id_len(auth: &GitAuth, len: usize) -> Stringy {
     let hash_input = format!("{}-{}-{}", auth.branch, auth.repo, auth.user);
     let hash = create_hash(hash_input);
-    let truncated_hash = truncate(&*hash, 8);
-    truncated_hash.into()
+    truncate(&*hash, len.clamp(1, 64))
 }
 
 impl fmt::Display for GitServer {
@@ -755,3 +1540,510 @@ impl fmt::Display for GitCredentials {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn credentials(users: &[&str]) -> GitCredentials {
+        GitCredentials {
+            auth_items: users
+                .iter()
+                .map(|user| GitAuth {
+                    user: Stringy::from(*user),
+                    repo: Stringy::from("repo"),
+                    branch: Stringy::from("main"),
+                    server: GitServer::GitHub,
+                    token: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn mode_of(path: &PathType) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[tokio::test]
+    async fn save_creates_private_file() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("artisan.cf"));
+
+        credentials(&["first"]).save(&path).await.unwrap();
+        assert_eq!(mode_of(&path), 0o600);
+    }
+
+    #[tokio::test]
+    async fn save_keeps_existing_mode() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("artisan.cf"));
+
+        credentials(&["first"]).save(&path).await.unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        credentials(&["second"]).save(&path).await.unwrap();
+
+        assert_eq!(mode_of(&path), 0o640);
+    }
+
+    #[tokio::test]
+    async fn failed_write_keeps_previous_credentials() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("artisan.cf"));
+        credentials(&["first"]).save(&path).await.unwrap();
+
+        // A directory where the temp file should go makes the write fail
+        fs::create_dir(format!("{}.tmp", path)).unwrap();
+        assert!(credentials(&["second"]).save(&path).await.is_err());
+
+        let loaded = GitCredentials::new(Some(&path)).await.unwrap();
+        assert_eq!(loaded, credentials(&["first"]));
+    }
+
+    /// Creates `<dir>/owner/repo.git` with one commit on `main` and returns
+    /// a server that serves it over `file://`
+    fn local_repo(dir: &TempDir) -> GitServer {
+        let work = dir.path().join("work");
+        let bare = dir.path().join("owner").join("repo.git");
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {:?} failed", args);
+        };
+
+        git(&["init", "-q", "-b", "main", work.to_str().unwrap()]);
+        fs::write(work.join("README"), "hello").unwrap();
+        git(&["-C", work.to_str().unwrap(), "add", "README"]);
+        git(&["-C", work.to_str().unwrap(), "commit", "-q", "-m", "init"]);
+        git(&["clone", "-q", "--bare", work.to_str().unwrap(), bare.to_str().unwrap()]);
+
+        GitServer::Custom(format!("file://{}", dir.path().display()))
+    }
+
+    fn clone_action(server: GitServer, destination: &PathType) -> GitAction {
+        GitAction::Clone {
+            repo_name: Stringy::from("repo"),
+            repo_owner: Stringy::from("owner"),
+            destination: destination.clone(),
+            repo_branch: Stringy::from("main"),
+            server,
+        }
+    }
+
+    #[tokio::test]
+    async fn clone_over_projected_limit_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let destination = PathType::PathBuf(dir.path().join("clone"));
+        let action = clone_action(GitServer::Custom("file:///nonexistent".into()), &destination);
+        let guard = DiskGuard {
+            max_repo_mb: Some(100),
+            min_free_mb: 0,
+        };
+
+        let result = guard
+            .clone_guarded_with(&action, async |_: &str, _: &str, _: &PathType| Ok(500))
+            .await;
+
+        let err = result.unwrap_err();
+        assert!(err.err_mesg.to_string().contains("exceeds the 100 MB limit"));
+        assert!(!destination.exists());
+    }
+
+    #[tokio::test]
+    async fn clone_is_not_probed_without_a_limit() {
+        let dir = TempDir::new().unwrap();
+        let server = local_repo(&dir);
+        let destination = PathType::PathBuf(dir.path().join("clone"));
+        let guard = DiskGuard {
+            max_repo_mb: None,
+            min_free_mb: 0,
+        };
+
+        guard
+            .clone_guarded_with(&clone_action(server, &destination), async |_: &str, _: &str, _: &PathType| {
+                panic!("probed without a limit")
+            })
+            .await
+            .unwrap();
+        assert!(destination.to_path_buf().join("README").exists());
+    }
+
+    #[tokio::test]
+    async fn probe_measures_and_cleans_up() {
+        let dir = TempDir::new().unwrap();
+        let server = local_repo(&dir);
+        let destination = PathType::PathBuf(dir.path().join("clone"));
+        let guard = DiskGuard {
+            max_repo_mb: Some(100),
+            min_free_mb: 0,
+        };
+
+        guard.clone_guarded(&clone_action(server, &destination)).await.unwrap();
+
+        assert!(destination.to_path_buf().join("README").exists());
+        assert!(!PathType::Content(format!("{}.probe", destination)).exists());
+    }
+
+    #[tokio::test]
+    async fn probe_over_the_cap_refuses_the_clone() {
+        let dir = TempDir::new().unwrap();
+        let server = local_repo(&dir);
+        let work = dir.path().join("work");
+        let bare = dir.path().join("owner").join("repo.git");
+
+        // Pseudo random so the pack can't compress it below the cap
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let blob: Vec<u8> = (0..8 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        fs::write(work.join("blob"), blob).unwrap();
+        for args in [
+            vec!["add", "blob"],
+            vec!["commit", "-q", "-m", "blob"],
+            vec!["push", "-q", bare.to_str().unwrap(), "main"],
+        ] {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(["-C", work.to_str().unwrap()])
+                .args(&args)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {:?} failed", args);
+        }
+
+        let destination = PathType::PathBuf(dir.path().join("clone"));
+        let probe = PathType::Content(format!("{}.probe", destination));
+        let url: String = server.repo_url("owner", "repo");
+        let projected: u64 = probe_clone_size_mb(&url, "main", &destination, 2).await.unwrap();
+        assert!(projected > 2);
+        assert!(!probe.exists());
+
+        let guard = DiskGuard {
+            max_repo_mb: Some(2),
+            min_free_mb: 0,
+        };
+        let err = guard.clone_guarded(&clone_action(server, &destination)).await.unwrap_err();
+        assert!(err.err_mesg.to_string().contains("exceeds the 2 MB limit"));
+        assert!(!destination.exists());
+        assert!(!probe.exists());
+    }
+
+    #[tokio::test]
+    async fn set_track_keeps_going_past_a_failed_branch() {
+        let dir = TempDir::new().unwrap();
+        let work = dir.path().join("work");
+        let bare = dir.path().join("owner").join("repo.git");
+        local_repo(&dir);
+        for branch in ["alpha", "beta"] {
+            let status = std::process::Command::new("git")
+                .args(["-C", work.to_str().unwrap(), "push", "-q", bare.to_str().unwrap()])
+                .arg(format!("main:{}", branch))
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        // The clone already has a local main, so tracking it fails
+        let clone = dir.path().join("clone");
+        let status = std::process::Command::new("git")
+            .args(["clone", "-q", bare.to_str().unwrap(), clone.to_str().unwrap()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let directory = PathType::PathBuf(clone);
+
+        let report = set_track(&directory).await.unwrap();
+        assert_eq!(report.succeeded, vec![Stringy::from("alpha"), Stringy::from("beta")]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, Stringy::from("main"));
+
+        let err = GitAction::SetTrack { directory }.execute().await.unwrap_err();
+        assert!(err.err_mesg.to_string().contains("main"));
+    }
+
+    #[tokio::test]
+    async fn fetch_is_streamed() {
+        let dir = TempDir::new().unwrap();
+        let server = local_repo(&dir);
+        let destination = PathType::PathBuf(dir.path().join("clone"));
+        clone_action(server, &destination).execute().await.unwrap();
+
+        // A new branch on the remote gives the fetch something to report
+        let work = dir.path().join("work");
+        let bare = dir.path().join("owner").join("repo.git");
+        let status = std::process::Command::new("git")
+            .args(["-C", work.to_str().unwrap(), "push", "-q", bare.to_str().unwrap(), "main:feature"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut lines: usize = 0;
+        GitAction::Fetch {
+            destination: destination.clone(),
+        }
+        .execute_streaming(|_, line| {
+            if line.contains("feature") {
+                lines += 1;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(lines, 1);
+
+        let missing = GitAction::Fetch {
+            destination: PathType::PathBuf(dir.path().join("missing")),
+        };
+        assert!(missing.execute_streaming(|_, _| ()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn streamed_output_is_not_buffered() {
+        const LINES: usize = 200_000;
+        let mut command = Command::new("sh");
+        command.args(["-c", &format!("yes output | head -n {}; yes error | head -n {} >&2; exit 1", LINES, LINES)]);
+
+        let mut stdout_lines: usize = 0;
+        let mut stderr_lines: usize = 0;
+        let mut recent: VecDeque<String> = VecDeque::new();
+        let err = stream_command(command, |stream, line| {
+            match stream {
+                GitStream::Stdout => stdout_lines += 1,
+                GitStream::Stderr => stderr_lines += 1,
+            }
+            if recent.len() == 10 {
+                recent.pop_front();
+            }
+            recent.push_back(line.to_owned());
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(stdout_lines, LINES);
+        assert_eq!(stderr_lines, LINES);
+        assert_eq!(recent.len(), 10);
+        // The error keeps only the tail of stderr, not everything it saw
+        assert_eq!(err.err_mesg.to_string().lines().count(), STREAMED_ERROR_LINES);
+    }
+
+    #[tokio::test]
+    async fn credentials_load_through_the_async_path() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("artisan.cf"));
+        credentials(&["first", "second"]).save(&path).await.unwrap();
+
+        let loaded = GitCredentials::load_async(Some(&path)).await.unwrap();
+        assert_eq!(loaded, credentials(&["first", "second"]));
+        assert_eq!(GitCredentials::new(Some(&path)).await.unwrap(), loaded);
+        assert_eq!(GitCredentials::try_load(&path).await.unwrap(), loaded);
+
+        let missing = PathType::PathBuf(dir.path().join("missing.cf"));
+        let err = GitCredentials::load_async(Some(&missing)).await.unwrap_err();
+        assert_eq!(err.err_type, Errors::InvalidFile);
+        assert!(matches!(
+            GitCredentials::try_load(&missing).await,
+            Err(PersistenceError::Io(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scheduler_bounds_concurrent_git_processes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        // Every clone connects to this listener, which holds the connection
+        // for a while and then hangs up, so each git process stays alive
+        // exactly as long as its connection is open
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::new(AtomicUsize::new(0));
+        {
+            let (active, peak, seen) = (active.clone(), peak.clone(), seen.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (active, peak) = (active.clone(), peak.clone());
+                    seen.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        drop(stream);
+                    });
+                }
+            });
+        }
+
+        let dir = TempDir::new().unwrap();
+        let scheduler = Arc::new(GitScheduler::new(3));
+        // `execute` futures are not `Send`, so the actions run on a local set
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let handles: Vec<_> = (0..20)
+                    .map(|i| {
+                        let scheduler = scheduler.clone();
+                        let action = clone_action(
+                            GitServer::Custom(format!("http://127.0.0.1:{}", port)),
+                            &PathType::PathBuf(dir.path().join(format!("clone-{}", i))),
+                        );
+                        tokio::task::spawn_local(async move { scheduler.run(action).await })
+                    })
+                    .collect();
+                for handle in handles {
+                    assert!(handle.await.unwrap().is_err());
+                }
+            })
+            .await;
+
+        assert_eq!(seen.load(Ordering::SeqCst), 20);
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert_eq!(scheduler.available(), 3);
+    }
+
+    #[tokio::test]
+    async fn ahead_behind_counts_both_sides() {
+        let dir = TempDir::new().unwrap();
+        let work = dir.path().join("work");
+        let bare = dir.path().join("owner").join("repo.git");
+        let clone = dir.path().join("clone");
+        local_repo(&dir);
+        let git = |cwd: &Path, args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .arg("-C")
+                .arg(cwd)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {:?} failed", args);
+        };
+
+        git(dir.path(), &["clone", "-q", bare.to_str().unwrap(), clone.to_str().unwrap()]);
+        for message in ["upstream one", "upstream two"] {
+            git(&work, &["commit", "-q", "--allow-empty", "-m", message]);
+        }
+        git(&work, &["push", "-q", bare.to_str().unwrap(), "main:main"]);
+        git(&clone, &["commit", "-q", "--allow-empty", "-m", "local"]);
+
+        let counts = check_ahead_behind(&PathType::PathBuf(clone)).await.unwrap();
+        assert_eq!(counts, AheadBehind { ahead: 1, behind: 2 });
+        assert!(counts.diverged());
+        assert!(!counts.can_fast_forward());
+    }
+
+    #[test]
+    fn ahead_behind_rejects_unexpected_output() {
+        assert_eq!(
+            AheadBehind::parse("2\t1\n").unwrap(),
+            AheadBehind { ahead: 1, behind: 2 }
+        );
+        assert!(AheadBehind::parse("").is_err());
+        assert!(AheadBehind::parse("2").is_err());
+        assert!(AheadBehind::parse("2 1 0").is_err());
+        assert!(AheadBehind::parse("two one").is_err());
+    }
+
+    fn auth(user: &str, server: GitServer, token: Option<&str>) -> GitAuth {
+        GitAuth {
+            user: Stringy::from(user),
+            repo: Stringy::from("repo"),
+            branch: Stringy::from("main"),
+            server,
+            token: token.map(Stringy::from),
+        }
+    }
+
+    #[test]
+    fn custom_server_keeps_port_and_nested_groups() {
+        let server = GitServer::Custom("https://git.example.com:8443/".into());
+
+        assert_eq!(
+            auth("group/subgroup", server.clone(), None).assemble_remote_url(),
+            "https://git.example.com:8443/group/subgroup/repo.git"
+        );
+        assert_eq!(
+            auth("/group//subgroup/", server.clone(), Some("secret")).assemble_remote_url(),
+            "https://secret@git.example.com:8443/group/subgroup/repo.git"
+        );
+        assert_eq!(
+            server.repo_url("group/subgroup", "repo.git"),
+            "https://git.example.com:8443/group/subgroup/repo.git"
+        );
+    }
+
+    #[test]
+    fn hosted_servers_build_flat_urls() {
+        assert_eq!(
+            auth("owner", GitServer::GitHub, None).assemble_remote_url(),
+            "https://github.com/owner/repo.git"
+        );
+        assert_eq!(
+            auth("owner", GitServer::GitLab, Some("secret")).assemble_remote_url(),
+            "https://secret@gitlab.com/owner/repo.git"
+        );
+    }
+
+    #[test]
+    fn project_ids_and_paths_follow_length_and_base() {
+        let auth = auth("owner", GitServer::GitHub, None);
+        let short = generate_git_project_id_len(&auth, 8);
+        let long = generate_git_project_id_len(&auth, 12);
+
+        assert_eq!(generate_git_project_id(&auth), short);
+        assert_eq!(short.len(), 8);
+        assert_eq!(long.len(), 12);
+        assert!(long.starts_with(&*short));
+        assert_eq!(generate_git_project_id_len(&auth, 0).len(), 1);
+        assert_eq!(generate_git_project_id_len(&auth, 100).len(), 64);
+
+        assert_eq!(
+            generate_git_project_path(&auth).to_path_buf(),
+            Path::new(PROJECT_BASE_DIR).join(&*short)
+        );
+        assert_eq!(
+            generate_git_project_path_in(&auth, &PathType::Str("/srv/projects".into()), 12)
+                .to_path_buf(),
+            Path::new("/srv/projects").join(&*long)
+        );
+    }
+
+    #[tokio::test]
+    async fn bootstrap_surfaces_a_corrupt_credentials_file() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("artisan.cf"));
+        fs::write(&path, "corrupt").unwrap();
+
+        let err = GitCredentials::bootstrap_git_credentials_from(&path).await.unwrap_err();
+        assert_eq!(err.err_type, Errors::InvalidFile);
+        assert!(err.err_mesg.to_string().contains("unreadable or corrupt"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "corrupt");
+    }
+
+    #[tokio::test]
+    async fn bootstrap_starts_empty_without_a_credentials_file() {
+        let dir = TempDir::new().unwrap();
+        let missing = PathType::PathBuf(dir.path().join("artisan.cf"));
+        assert!(GitCredentials::bootstrap_git_credentials_from(&missing)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let path = PathType::PathBuf(dir.path().join("saved.cf"));
+        credentials(&["first"]).save(&path).await.unwrap();
+        let loaded = GitCredentials::bootstrap_git_credentials_from(&path).await.unwrap();
+        assert_eq!(loaded, credentials(&["first"]));
+        assert!(!loaded.is_empty());
+    }
+}
@@ -8,15 +8,21 @@ use std::{
     io::{Read, Write},
     time::Duration,
 };
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 
 use crate::{
-    encryption::encrypt_text, timestamp::current_timestamp
+    encryption::{decrypt_expecting, encrypt_tagged, encrypt_text, ContentType},
+    state_persistence::PersistenceError,
+    timestamp::current_timestamp,
 };
 
 pub const IDENTITYPATHSTR: &str = "/opt/artisan/identity";
 pub const HASH_LENGTH: usize = 28;
 pub const CUSTOM_EPOCH: u64 = 1_047_587_400;
+/// How long `generate_id` waits for the clock to tick before giving up
+pub const MAX_CLOCK_WAIT: Duration = Duration::from_secs(3);
+/// How long `generate_id` sleeps between clock reads while waiting for it to tick
+const CLOCK_POLL_INTERVAL: Duration = Duration::from_millis(5);
 
 pub struct SnowflakeIDGenerator {
     custom_epoch: u64,
@@ -24,18 +30,24 @@ pub struct SnowflakeIDGenerator {
     machine_id: u8,
     sequence: u16,
     last_timestamp: u64,
+    clock: fn() -> u64,
+    max_clock_wait: Duration,
 }
 
 impl SnowflakeIDGenerator {
-    pub fn new(datacenter_id: u8, machine_id: u8) -> Result<Self, ()> {
+    pub fn new(datacenter_id: u8, machine_id: u8) -> Result<Self, ErrorArrayItem> {
         if datacenter_id > 31 {
-            log!(LogLevel::Error, "Datacenter ID must be between 0 and 31");
-            return Err(());
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidType,
+                "Datacenter ID must be between 0 and 31".to_owned(),
+            ));
         }
 
         if machine_id > 31 {
-            log!(LogLevel::Error, "Machine ID must be between 0 and 31");
-            return Err(());
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidType,
+                "Machine ID must be between 0 and 31".to_owned(),
+            ));
         }
 
         Ok(Self {
@@ -44,36 +56,80 @@ impl SnowflakeIDGenerator {
             machine_id,
             sequence: 0,
             last_timestamp: 0,
+            clock: current_timestamp,
+            max_clock_wait: MAX_CLOCK_WAIT,
         })
     }
 
-    fn wait_for_next_millis(last_timestamp: u64) -> u64 {
-        let mut timestamp = current_timestamp();
+    /// Replaces the timestamp source, mainly so tests can freeze the clock
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets how long to wait for the clock to move before giving up
+    pub fn with_max_clock_wait(mut self, max_clock_wait: Duration) -> Self {
+        self.max_clock_wait = max_clock_wait;
+        self
+    }
+
+    /// Waits until the clock passes `last_timestamp`, sleeping
+    /// `CLOCK_POLL_INTERVAL` between reads. The clock only ticks once a
+    /// second, so polling any faster would just spin a core. Errors if the
+    /// clock is still stuck after `max_clock_wait`.
+    async fn wait_for_next_millis(&self, last_timestamp: u64) -> Result<u64, ErrorArrayItem> {
+        let deadline: Instant = Instant::now() + self.max_clock_wait;
+        let mut timestamp = (self.clock)();
         while timestamp <= last_timestamp {
-            timestamp = current_timestamp();
+            if Instant::now() >= deadline {
+                return Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!(
+                        "Clock didn't advance within {:?}, refusing to generate ID",
+                        self.max_clock_wait
+                    ),
+                ));
+            }
+            sleep(CLOCK_POLL_INTERVAL).await;
+            timestamp = (self.clock)();
         }
-        timestamp
+        Ok(timestamp)
     }
 
+    /// Generates an ID, returning 0 if the clock moved backwards or stalled.
+    /// Use `try_generate_id` to get the reason.
     pub async fn generate_id(&mut self) -> u64 {
-        let mut timestamp = current_timestamp();
+        match self.try_generate_id().await {
+            Ok(id) => id,
+            Err(err) => {
+                log!(LogLevel::Error, "{}", err.err_mesg);
+                0
+            }
+        }
+    }
+
+    pub async fn try_generate_id(&mut self) -> Result<u64, ErrorArrayItem> {
+        let mut timestamp = (self.clock)();
 
         if timestamp < self.last_timestamp {
             sleep(Duration::from_millis(10)).await;
+            timestamp = (self.clock)();
             if timestamp < self.last_timestamp {
-                log!(
-                    LogLevel::Error,
-                    "Clock moved backwards. Refusing to generate ID."
-                );
-                return 0;
+                return Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    "Clock moved backwards. Refusing to generate ID.".to_owned(),
+                ));
             }
         }
 
         if timestamp == self.last_timestamp {
-            self.sequence = (self.sequence + 1) & 0xFFF; // 12 bits max
-            if self.sequence == 0 {
-                timestamp = Self::wait_for_next_millis(self.last_timestamp);
+            let sequence: u16 = (self.sequence + 1) & 0xFFF; // 12 bits max
+            if sequence == 0 {
+                // Only take the wrapped sequence once the clock has moved,
+                // otherwise a retry would hand out an ID already used
+                timestamp = self.wait_for_next_millis(self.last_timestamp).await?;
             }
+            self.sequence = sequence;
         } else {
             self.sequence = 0;
         }
@@ -81,10 +137,10 @@ impl SnowflakeIDGenerator {
         self.last_timestamp = timestamp;
 
         // Construct the 64-bit ID
-        ((timestamp - self.custom_epoch) << 22)
+        Ok(((timestamp - self.custom_epoch) << 22)
             | ((self.datacenter_id as u64) << 17)
             | ((self.machine_id as u64) << 12)
-            | (self.sequence as u64)
+            | (self.sequence as u64))
     }
     
     // fn make_transit_safe(&self) -> Stringy {
@@ -108,11 +164,9 @@ impl Identifier {
         let datacenter_id = rand::thread_rng().gen_range(1..=5);
         let machine_id = rand::thread_rng().gen_range(1..=5);
 
-        let mut big_id: SnowflakeIDGenerator = SnowflakeIDGenerator::new(datacenter_id, machine_id).map_err(
-            |_| ErrorArrayItem::new(Errors::GeneralError, "Error generating system id".to_owned()),
-        )?;
+        let mut big_id: SnowflakeIDGenerator = SnowflakeIDGenerator::new(datacenter_id, machine_id)?;
 
-        let id = big_id.generate_id().await;
+        let id = big_id.try_generate_id().await?;
 
         Ok(Self {
             id,
@@ -123,25 +177,25 @@ impl Identifier {
     pub async fn verify(&self) -> bool {
         let given_signature = self._signature.clone();
         let new_signature = Self::generate_signature(self.id);
-        return match given_signature == new_signature {
+        match given_signature == new_signature {
             true => true,
             false => false,
-        };
+        }
     }
 
     /// loads the identifier from disk, creates a new one if needed
     pub async fn load() -> Result<Option<Self>, ErrorArrayItem> {
         let identifier_path: PathType = PathType::Str(IDENTITYPATHSTR.into());
         if identifier_path.exists() {
-            match Self::load_from_file() {
-                Ok(data) => return Ok(Some(data)),
+            match Self::try_load(&identifier_path).await {
+                Ok(data) => Ok(Some(data)),
                 Err(err) => {
                     log!(LogLevel::Trace, "ERROR: Failed to load identy: {}", err);
-                    return Ok(None);
+                    Ok(None)
                 }
             }
         } else {
-            return Ok(None);
+            Ok(None)
         }
     }
 
@@ -162,6 +216,33 @@ impl Identifier {
         Ok(identifier)
     }
 
+    /// Saves the identifier encrypted in an `Identity` envelope, reporting
+    /// which step failed as a `PersistenceError`
+    pub async fn try_save(&self, path: &PathType) -> Result<(), PersistenceError> {
+        let serialized_id =
+            serde_json::to_string_pretty(&self).map_err(|e| PersistenceError::Serde(e.to_string()))?;
+        let encrypted_id: Vec<u8> = encrypt_tagged(serialized_id.as_bytes(), ContentType::Identity)
+            .await
+            .map_err(|e| PersistenceError::Encrypt(e.err_mesg.to_string()))?;
+        std::fs::write(path, encrypted_id)?;
+        Ok(())
+    }
+
+    /// Loads an identifier saved by `try_save`, reporting which step failed
+    /// as a `PersistenceError`. Plain JSON files written by `save_to_file`
+    /// are still accepted.
+    pub async fn try_load(path: &PathType) -> Result<Self, PersistenceError> {
+        let content = std::fs::read_to_string(path)?;
+        if content.trim_start().starts_with('{') {
+            return serde_json::from_str(&content).map_err(|e| PersistenceError::Serde(e.to_string()));
+        }
+
+        let decrypted: Vec<u8> = decrypt_expecting(content.trim_end().as_bytes(), ContentType::Identity)
+            .await
+            .map_err(|e| PersistenceError::Decrypt(e.err_mesg.to_string()))?;
+        serde_json::from_slice(&decrypted).map_err(|e| PersistenceError::Serde(e.to_string()))
+    }
+
     /// Return a JSON string representation of the Identifier fields
     pub fn to_json(&self) -> Result<String, ErrorArrayItem> {
         let json_representation = serde_json::to_string_pretty(self)?;
@@ -188,3 +269,76 @@ impl Identifier {
         log!(LogLevel::Debug, "SIG: {}", self._signature);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn identity_round_trips_encrypted() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("identity"));
+        let identity = Identifier::new().await.unwrap();
+
+        identity.try_save(&path).await.unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains(&identity.id.to_string()));
+
+        let loaded = Identifier::try_load(&path).await.unwrap();
+        assert_eq!(loaded.id, identity.id);
+        assert!(loaded.verify().await);
+    }
+
+    #[tokio::test]
+    async fn plain_json_identity_still_loads() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("identity"));
+        let identity = Identifier::new().await.unwrap();
+        std::fs::write(&path, identity.to_json().unwrap()).unwrap();
+
+        assert_eq!(Identifier::try_load(&path).await.unwrap().id, identity.id);
+    }
+
+    #[tokio::test]
+    async fn other_envelopes_are_not_loaded_as_identity() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("identity"));
+        let blob = encrypt_tagged(b"{}", ContentType::AppState).await.unwrap();
+        std::fs::write(&path, blob).unwrap();
+
+        let err = Identifier::try_load(&path).await.unwrap_err();
+        assert!(err.to_string().contains("Content type mismatch"));
+    }
+
+    static FROZEN_CLOCK_READS: AtomicUsize = AtomicUsize::new(0);
+
+    fn frozen_clock() -> u64 {
+        FROZEN_CLOCK_READS.fetch_add(1, Ordering::SeqCst);
+        CUSTOM_EPOCH + 100
+    }
+
+    #[tokio::test]
+    async fn frozen_clock_is_polled_not_spun() {
+        let mut generator = SnowflakeIDGenerator::new(1, 1)
+            .unwrap()
+            .with_clock(frozen_clock)
+            .with_max_clock_wait(Duration::from_millis(100));
+
+        let mut ids: HashSet<u64> = HashSet::new();
+        for _ in 0..4096 {
+            assert!(ids.insert(generator.try_generate_id().await.unwrap()));
+        }
+
+        // The sequence is used up and the clock never moves
+        let reads_before: usize = FROZEN_CLOCK_READS.load(Ordering::SeqCst);
+        assert!(generator.try_generate_id().await.is_err());
+        let reads: usize = FROZEN_CLOCK_READS.load(Ordering::SeqCst) - reads_before;
+        assert!(reads < 100, "clock was read {} times in 100ms", reads);
+
+        // A failed wait doesn't hand out an ID that was already used
+        assert!(generator.try_generate_id().await.is_err());
+    }
+}
+
@@ -1,7 +1,18 @@
 use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::future::Future;
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
 use dusa_collection_utils::log;
 use dusa_collection_utils::log::LogLevel;
+use serde::{de::DeserializeOwned, Serialize};
+use simple_comms::network::send_receive::{receive_message, send_data, send_message};
+use simple_comms::protocol::header::{ProtocolHeader, EOL, HEADER_LENGTH};
+use simple_comms::protocol::{flags::Flags, message::ProtocolMessage, proto::Proto, status::ProtocolStatus};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use trust_dns_resolver::{config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts}, AsyncResolver};
 
 use crate::version::aml_version;
@@ -41,11 +52,11 @@ pub async fn resolve_url(url: &str, resolver_addr: Option<IpAddr>) -> Result<Opt
     match resolver.lookup_ip(url).await {
         Ok(response) => {
             let ips: Vec<_> = response.iter().collect();
-            return Ok(Some(ips))
+            Ok(Some(ips))
         },
         Err(err) => {
             log!(LogLevel::Error, "Failed to resolve {}: {}", url, err);
-            return Ok(None)
+            Ok(None)
         },
     }
 }
@@ -53,4 +64,470 @@ pub async fn resolve_url(url: &str, resolver_addr: Option<IpAddr>) -> Result<Opt
 pub fn get_header_version() -> u16 {
     let lib_version = aml_version();
     lib_version.encode()
-}
\ No newline at end of file
+}
+
+/// Decodes the header of a captured frame from its hex dump.
+///
+/// Accepts either a header on its own or a full frame, anything after the
+/// header is ignored. Whitespace and a leading `0x` are stripped first so
+/// dumps can be pasted as they are.
+pub fn decode_frame_header(hex: &str) -> Result<ProtocolHeader, ErrorArrayItem> {
+    let cleaned: String = hex.split_whitespace().collect();
+    let cleaned: &str = cleaned.strip_prefix("0x").unwrap_or(&cleaned);
+
+    let bytes: Vec<u8> = hex::decode(cleaned).map_err(|err| {
+        ErrorArrayItem::new(Errors::InvalidChunkData, format!("Invalid hex: {}", err))
+    })?;
+
+    parse_header(&bytes)
+}
+
+/// Parses the fixed size header at the start of `bytes`
+fn parse_header(bytes: &[u8]) -> Result<ProtocolHeader, ErrorArrayItem> {
+    if bytes.len() < HEADER_LENGTH {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidChunkData,
+            format!(
+                "Frame is {} bytes, a header needs {}",
+                bytes.len(),
+                HEADER_LENGTH
+            ),
+        ));
+    }
+
+    let mut origin_address: [u8; 4] = [0u8; 4];
+    origin_address.copy_from_slice(&bytes[13..17]);
+    let mut encryption_key: [u8; 32] = [0u8; 32];
+    encryption_key.copy_from_slice(&bytes[17..49]);
+
+    Ok(ProtocolHeader {
+        version: u16::from_be_bytes([bytes[0], bytes[1]]),
+        flags: bytes[2],
+        payload_length: u64::from_be_bytes([
+            bytes[3], bytes[4], bytes[5], bytes[6], bytes[7], bytes[8], bytes[9], bytes[10],
+        ]),
+        reserved: bytes[11],
+        status: bytes[12],
+        origin_address,
+        encryption_key,
+    })
+}
+
+/// Largest payload `receive_framed` will allocate for in `LengthPrefixed` mode
+pub const MAX_FRAME_PAYLOAD: u64 = 64 * 1024 * 1024;
+
+/// How `receive_framed` finds where a message ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FramingMode {
+    /// Scan for the `-EOL-` delimiter, like `receive_message`. A payload
+    /// containing those bytes gets cut short.
+    #[default]
+    DelimitedEol,
+    /// Read the header, then exactly `payload_length` bytes. The trailing
+    /// delimiter senders still append is checked but never scanned for.
+    LengthPrefixed,
+}
+
+/// Receives a single message using the given framing.
+///
+/// Both modes read frames produced by `ProtocolMessage::format`, so only
+/// the receiving side has to opt in to `LengthPrefixed`. No reply is sent.
+pub async fn receive_framed<STREAM, RESPONSE>(
+    stream: &mut STREAM,
+    framing: FramingMode,
+    proto: Proto,
+) -> io::Result<ProtocolMessage<RESPONSE>>
+where
+    STREAM: AsyncReadExt + AsyncWriteExt + Unpin,
+    RESPONSE: DeserializeOwned + Serialize + Debug + Clone + Display,
+{
+    if framing == FramingMode::DelimitedEol {
+        return receive_message::<STREAM, RESPONSE>(stream, false, proto).await;
+    }
+
+    let mut frame: Vec<u8> = vec![0u8; HEADER_LENGTH];
+    stream.read_exact(&mut frame).await?;
+
+    let header: ProtocolHeader = parse_header(&frame)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.err_mesg.to_string()))?;
+
+    if header.payload_length > MAX_FRAME_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Payload of {} bytes exceeds the {} byte limit",
+                header.payload_length, MAX_FRAME_PAYLOAD
+            ),
+        ));
+    }
+
+    let mut payload: Vec<u8> = vec![0u8; header.payload_length as usize];
+    stream.read_exact(&mut payload).await?;
+    frame.extend_from_slice(&payload);
+
+    let mut delimiter: Vec<u8> = vec![0u8; EOL.len()];
+    stream.read_exact(&mut delimiter).await?;
+    if delimiter != EOL.as_bytes() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame is not followed by the end of line delimiter",
+        ));
+    }
+
+    ProtocolMessage::<RESPONSE>::from_bytes(&frame).await
+}
+
+/// Which flags a message should carry for the transport it travels over.
+/// Unix sockets never leave the host so they go out plain, anything over
+/// tcp is encrypted and signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityProfile {
+    Unix,
+    Tcp,
+}
+
+impl SecurityProfile {
+    pub fn flags(&self) -> Flags {
+        match self {
+            SecurityProfile::Unix => Flags::NONE,
+            SecurityProfile::Tcp => Flags::ENCRYPTED | Flags::SIGNATURE,
+        }
+    }
+
+    pub fn proto(&self) -> Proto {
+        match self {
+            SecurityProfile::Unix => Proto::UNIX,
+            SecurityProfile::Tcp => Proto::TCP,
+        }
+    }
+}
+
+impl From<Proto> for SecurityProfile {
+    fn from(proto: Proto) -> Self {
+        match proto {
+            Proto::UNIX => SecurityProfile::Unix,
+            Proto::TCP => SecurityProfile::Tcp,
+        }
+    }
+}
+
+/// Lets the receiving side see how a message travelled.
+///
+/// `receive_message` reverses the compression, encryption and signing
+/// before handing the message over, but leaves the header untouched, so
+/// the flags reported here are the ones the sender set.
+pub trait AppliedFlags {
+    fn applied_flags(&self) -> Flags;
+}
+
+impl<T> AppliedFlags for ProtocolMessage<T> {
+    fn applied_flags(&self) -> Flags {
+        Flags::from_bits_truncate(self.header.flags)
+    }
+}
+
+/// How long `send_message_with_timeout` waits by default before giving up on a peer
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wraps `send_message` with a deadline on the whole exchange.
+///
+/// `send_message` waits for the peer's reply with no limit, so a peer that
+/// crashes after accepting but before replying would hang the caller. When
+/// the deadline passes the stream is shut down and `ProtocolStatus::TIMEDOUT`
+/// is returned.
+pub async fn send_message_with_timeout<STREAM, DATA, RESPONSE>(
+    stream: &mut STREAM,
+    flags: Flags,
+    data: DATA,
+    proto: Proto,
+    insecure: bool,
+    response_timeout: Duration,
+) -> Result<Result<ProtocolMessage<RESPONSE>, ProtocolStatus>, io::Error>
+where
+    STREAM: AsyncReadExt + AsyncWriteExt + Unpin,
+    DATA: serde::de::DeserializeOwned + std::fmt::Debug + serde::Serialize + Clone + Unpin,
+    RESPONSE: serde::de::DeserializeOwned + std::fmt::Debug + serde::Serialize + Clone + Unpin,
+{
+    let result = tokio::time::timeout(
+        response_timeout,
+        send_message::<STREAM, DATA, RESPONSE>(stream, flags, data, proto, insecure),
+    )
+    .await;
+
+    match result {
+        Ok(response) => response,
+        Err(_) => {
+            log!(
+                LogLevel::Warn,
+                "No response received within {:?}, dropping connection",
+                response_timeout
+            );
+            if let Err(err) = stream.shutdown().await {
+                log!(LogLevel::Trace, "Failed to shutdown timed out stream: {}", err);
+            }
+            Ok(Err(ProtocolStatus::TIMEDOUT))
+        }
+    }
+}
+
+/// A listener `serve` can accept protocol connections from
+pub trait ProtocolListener {
+    type Stream: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static;
+
+    fn accept_stream(&self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+impl ProtocolListener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().await.map(|(stream, _)| stream)
+    }
+}
+
+impl ProtocolListener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_stream(&self) -> io::Result<TcpStream> {
+        self.accept().await.map(|(stream, _)| stream)
+    }
+}
+
+/// Accepts connections forever, answering every message with the handler's response.
+///
+/// Each connection runs in its own task and keeps being read until the peer
+/// hangs up, so a client that misbehaves or drops mid message only ends its
+/// own connection. Responses are sent without flags since `send_message`
+/// parses replies with the frame delimiter still attached, which only an
+/// untransformed payload tolerates. Abort the task running `serve` to stop
+/// the server.
+pub async fn serve<L, F, Fut, REQ, RESP>(listener: L, proto: Proto, handler: F)
+where
+    L: ProtocolListener,
+    F: Fn(ProtocolMessage<REQ>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = RESP> + Send,
+    REQ: DeserializeOwned + Serialize + Debug + Clone + Display + Send + 'static,
+    RESP: DeserializeOwned + Serialize + Debug + Clone + Send + 'static,
+{
+    let handler: Arc<F> = Arc::new(handler);
+
+    loop {
+        let mut stream: L::Stream = match listener.accept_stream().await {
+            Ok(stream) => stream,
+            Err(err) => {
+                log!(LogLevel::Error, "Failed to accept connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let handler: Arc<F> = handler.clone();
+        tokio::spawn(async move {
+            loop {
+                let request: ProtocolMessage<REQ> =
+                    match receive_message::<L::Stream, REQ>(&mut stream, false, proto).await {
+                        Ok(message) => message,
+                        Err(err) => {
+                            if err.kind() != io::ErrorKind::UnexpectedEof {
+                                log!(LogLevel::Warn, "Dropping connection: {}", err);
+                            }
+                            break;
+                        }
+                    };
+
+                let response: RESP = handler(request).await;
+
+                let response_bytes: Vec<u8> = match ProtocolMessage::new(Flags::NONE, response) {
+                    Ok(message) => match message.format().await {
+                        Ok(bytes) => bytes,
+                        Err(err) => {
+                            log!(LogLevel::Error, "Failed to serialize response: {}", err);
+                            break;
+                        }
+                    },
+                    Err(err) => {
+                        log!(LogLevel::Error, "Failed to create response: {}", err);
+                        break;
+                    }
+                };
+
+                if let Err(err) = send_data(&mut stream, response_bytes, proto).await {
+                    log!(LogLevel::Warn, "Failed to send response: {}", err);
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let (mut client, mut peer) = tokio::io::duplex(64 * 1024);
+
+        let result = send_message_with_timeout::<_, String, String>(
+            &mut client,
+            Flags::NONE,
+            "ping".to_string(),
+            Proto::UNIX,
+            false,
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.unwrap_err(), ProtocolStatus::TIMEDOUT);
+
+        // The sender shut its side down, so the peer sees the request and then EOF
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), peer.read_to_end(&mut received))
+            .await
+            .expect("stream was not shut down")
+            .unwrap();
+        assert!(!received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn serve_answers_and_survives_bad_clients() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("serve.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(serve(
+            listener,
+            Proto::UNIX,
+            |request: ProtocolMessage<String>| async move { format!("echo {}", request.payload) },
+        ));
+
+        // A client that sends garbage and hangs up only ends its own connection
+        let mut bad = UnixStream::connect(&path).await.unwrap();
+        bad.write_all(b"not a protocol frame").await.unwrap();
+        drop(bad);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        for word in ["one", "two"] {
+            let response = send_message::<_, String, String>(
+                &mut client,
+                Flags::NONE,
+                word.to_string(),
+                Proto::UNIX,
+                false,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(response.payload, format!("echo {}", word));
+        }
+
+        server.abort();
+    }
+
+    /// Formats a frame and returns it with the header version the sender set
+    async fn captured_frame(flags: Flags) -> (u16, Vec<u8>) {
+        let mut message = ProtocolMessage::new(flags, "hello".to_string()).unwrap();
+        message.header.status = ProtocolStatus::OK.bits();
+        let version: u16 = message.header.version;
+        (version, message.format().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn frame_header_decodes_from_a_hex_dump() {
+        let (version, bytes) = captured_frame(Flags::COMPRESSED).await;
+
+        let header = decode_frame_header(&hex::encode(&bytes)).unwrap();
+        assert_eq!(header.version, version);
+        assert_eq!(header.flags, Flags::COMPRESSED.bits());
+        assert_eq!(header.status, ProtocolStatus::OK.bits());
+        assert_eq!(
+            header.payload_length as usize,
+            bytes.len() - HEADER_LENGTH - EOL.len()
+        );
+
+        // A header on its own, pasted with a prefix and line breaks
+        let dump = hex::encode(&bytes[..HEADER_LENGTH]);
+        let (first, second) = dump.split_at(20);
+        let header_only = decode_frame_header(&format!("0x{}\n  {}", first, second)).unwrap();
+        assert_eq!(header_only.version, header.version);
+        assert_eq!(header_only.flags, header.flags);
+        assert_eq!(header_only.status, header.status);
+        assert_eq!(header_only.payload_length, header.payload_length);
+    }
+
+    #[test]
+    fn short_or_invalid_dumps_are_rejected() {
+        assert!(decode_frame_header("00ff").is_err());
+        assert!(decode_frame_header("not hex").is_err());
+    }
+
+    #[tokio::test]
+    async fn received_message_reports_the_senders_flags() {
+        let (_, bytes) = captured_frame(Flags::ENCRYPTED | Flags::COMPRESSED).await;
+        let (mut sender, mut receiver) = tokio::io::duplex(64 * 1024);
+        sender.write_all(&bytes).await.unwrap();
+
+        let message = receive_message::<_, String>(&mut receiver, false, Proto::UNIX)
+            .await
+            .unwrap();
+
+        assert_eq!(message.payload, "hello");
+        assert!(message
+            .applied_flags()
+            .contains(Flags::ENCRYPTED | Flags::COMPRESSED));
+        assert!(!message.applied_flags().contains(Flags::SIGNATURE));
+    }
+
+    async fn frame_with_delimiter_in_payload() -> Vec<u8> {
+        let message = ProtocolMessage::new(Flags::NONE, format!("before{}after", EOL)).unwrap();
+        message.format().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_reads_past_a_delimiter_in_the_payload() {
+        let bytes = frame_with_delimiter_in_payload().await;
+        let (mut sender, mut receiver) = tokio::io::duplex(64 * 1024);
+        sender.write_all(&bytes).await.unwrap();
+        sender.write_all(&bytes).await.unwrap();
+
+        for _ in 0..2 {
+            let message = receive_framed::<_, String>(
+                &mut receiver,
+                FramingMode::LengthPrefixed,
+                Proto::UNIX,
+            )
+            .await
+            .unwrap();
+            assert_eq!(message.payload, format!("before{}after", EOL));
+        }
+    }
+
+    #[tokio::test]
+    async fn delimited_framing_cuts_the_payload_short() {
+        let bytes = frame_with_delimiter_in_payload().await;
+        let (mut sender, mut receiver) = tokio::io::duplex(64 * 1024);
+        sender.write_all(&bytes).await.unwrap();
+        drop(sender);
+
+        // Scanning stops at the delimiter inside the payload, which then fails to parse
+        let result = receive_framed::<_, String>(
+            &mut receiver,
+            FramingMode::DelimitedEol,
+            Proto::UNIX,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_refuses_oversized_payloads() {
+        let mut bytes = frame_with_delimiter_in_payload().await;
+        bytes[3..11].copy_from_slice(&(MAX_FRAME_PAYLOAD + 1).to_be_bytes());
+        let (mut sender, mut receiver) = tokio::io::duplex(64 * 1024);
+        sender.write_all(&bytes).await.unwrap();
+
+        let err = receive_framed::<_, String>(&mut receiver, FramingMode::LengthPrefixed, Proto::UNIX)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
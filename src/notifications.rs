@@ -6,12 +6,22 @@ use dusa_collection_utils::{
     stringy::Stringy,
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use simple_comms::{network::send_receive::send_message, protocol::{flags::Flags, proto::Proto, status::ProtocolStatus}};
-use std::fmt;
+use std::{fmt, time::Duration};
 use tokio::net::TcpStream;
 
+use crate::network::send_message_with_timeout;
+
 const MAIL_ADDRESS: &str = "45.137.192.70:1827";
 
+/// Reply status the mail server sends for an email it accepted
+pub const MAIL_ACCEPTED: ProtocolStatus = ProtocolStatus::OK;
+
+/// Reply status the mail server sends for an email whose idempotency key it
+/// had already accepted. The earlier copy was delivered so this one is dropped.
+pub const MAIL_DUPLICATE: ProtocolStatus = ProtocolStatus::OK.union(ProtocolStatus::REFUSED);
+
 /// Represents an email message.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Email {
@@ -19,6 +29,10 @@ pub struct Email {
     pub subject: Stringy,
     /// The body of the email.
     pub body: Stringy,
+    /// Lets the mail server drop a resend of a message it already accepted.
+    /// Set by `send_with_retry`, left off the wire when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<Stringy>,
 }
 
 // Display implementation for Email
@@ -36,7 +50,22 @@ impl fmt::Display for Email {
 impl Email {
     /// Creates a new Email instance with the given subject and body.
     pub fn new(subject: Stringy, body: Stringy) -> Self {
-        Email { subject, body }
+        Email {
+            subject,
+            body,
+            idempotency_key: None,
+        }
+    }
+
+    /// Hash of the destination, subject, body and a caller supplied nonce.
+    /// Sending the same email with the same nonce always yields the same key.
+    pub fn idempotency_key_for(&self, destination: &str, nonce: &str) -> Stringy {
+        let mut hasher = Sha256::new();
+        for part in [destination, &self.subject, &self.body, nonce] {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part.as_bytes());
+        }
+        Stringy::from(hex::encode(hasher.finalize()))
     }
 
     /// Checks if the email data is valid.
@@ -46,12 +75,12 @@ impl Email {
 
     /// Converts the email to JSON format.
     pub fn to_json(&self) -> Result<String, ErrorArrayItem> {
-        serde_json::to_string(self).map_err(|err| ErrorArrayItem::from(err))
+        serde_json::to_string(self).map_err(ErrorArrayItem::from)
     }
 
     /// Creates an Email instance from JSON data.
     pub fn from_json(json_data: &str) -> Result<Self, ErrorArrayItem> {
-        serde_json::from_str(json_data).map_err(|err| ErrorArrayItem::from(err))
+        serde_json::from_str(json_data).map_err(ErrorArrayItem::from)
     }
 
     /// Sends the email data over a TCP stream.
@@ -97,7 +126,7 @@ impl Email {
                                     ProtocolStatus::from_bits_truncate(response.header.status))
                             );
 
-                        return UnifiedResult::new(Ok(OkWarning{
+                        UnifiedResult::new(Ok(OkWarning{
                             data: response.payload,
                             warning: WarningArray::new(vec![warning]),
                         }))
@@ -106,13 +135,206 @@ impl Email {
                         let error: ErrorArrayItem = 
                             ErrorArrayItem::new(Errors::Network, format!("{}", error_code));
                         
-                        return UnifiedResult::new(Err(error))
+                        UnifiedResult::new(Err(error))
                     },
                 }
             },
             Err(err) => {
-                return UnifiedResult::new(Err(ErrorArrayItem::from(err)))
+                UnifiedResult::new(Err(ErrorArrayItem::from(err)))
             },
         }
     }
+
+    /// Sends the email, retrying up to `attempts` times with `delay` between tries.
+    ///
+    /// The email is stamped with an idempotency key built from `nonce` before
+    /// the first try, so a retry after a timeout that did deliver is dropped by
+    /// the server instead of reaching the recipient twice. Each try waits at
+    /// most `response_timeout` for the reply. Only a `MAIL_ACCEPTED` or
+    /// `MAIL_DUPLICATE` reply counts as success, any other status is retried.
+    /// Reuse the nonce only when resending the same logical email.
+    pub async fn send_with_retry(
+        &self,
+        addr: Option<&str>,
+        nonce: &str,
+        attempts: u32,
+        delay: Duration,
+        response_timeout: Duration,
+    ) -> UnifiedResult<OkWarning<()>> {
+        if !self.is_valid() {
+            return UnifiedResult::new(Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Invalid Email Data".to_owned(),
+            )));
+        }
+
+        let mut email: Email = self.clone();
+        if email.idempotency_key.is_none() {
+            let destination: &str = addr.unwrap_or(MAIL_ADDRESS);
+            email.idempotency_key = Some(email.idempotency_key_for(destination, nonce));
+        }
+
+        let attempts: u32 = attempts.max(1);
+        let mut attempt: u32 = 1;
+        loop {
+            match email.send_attempt(addr, response_timeout).await {
+                Ok(()) => {
+                    return UnifiedResult::new(Ok(OkWarning {
+                        data: (),
+                        warning: WarningArray::new(vec![]),
+                    }))
+                }
+                Err(err) if attempt >= attempts => return UnifiedResult::new(Err(err)),
+                Err(err) => log!(
+                    LogLevel::Warn,
+                    "Email send attempt {}/{} failed ({}), retrying in {:?}",
+                    attempt,
+                    attempts,
+                    err,
+                    delay
+                ),
+            }
+
+            attempt += 1;
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// A single delivery attempt for `send_with_retry`
+    async fn send_attempt(&self, addr: Option<&str>, response_timeout: Duration) -> Result<(), ErrorArrayItem> {
+        let mut stream: TcpStream = TcpStream::connect(addr.unwrap_or(MAIL_ADDRESS)).await?;
+        let data: String = self.to_json()?;
+
+        let response = send_message_with_timeout::<TcpStream, String, ()>(
+            &mut stream,
+            Flags::OPTIMIZED,
+            data,
+            Proto::TCP,
+            false,
+            response_timeout,
+        )
+        .await?;
+
+        let status: ProtocolStatus = match response {
+            Ok(response) => ProtocolStatus::from_bits_truncate(response.header.status),
+            Err(status) => status,
+        };
+
+        if status == MAIL_ACCEPTED {
+            Ok(())
+        } else if status == MAIL_DUPLICATE {
+            log!(LogLevel::Debug, "Mail server already accepted this email, not resending");
+            Ok(())
+        } else {
+            Err(ErrorArrayItem::new(
+                Errors::Network,
+                format!("Mail server replied with {}", status),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_comms::network::send_receive::{create_response, receive_message, send_data};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// What the mock mail server has seen
+    #[derive(Default)]
+    struct Mailbox {
+        keys: HashSet<String>,
+        delivered: usize,
+        connections: usize,
+    }
+
+    /// Dedupes on the idempotency key like the real server. The first
+    /// connection is accepted but never answered, as if the reply was lost.
+    /// With `always_error` every request is answered with an error instead.
+    async fn spawn_mail_server(always_error: bool) -> (String, Arc<Mutex<Mailbox>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mailbox = Arc::new(Mutex::new(Mailbox::default()));
+
+        let shared = mailbox.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mailbox = shared.clone();
+                tokio::spawn(async move {
+                    let message = receive_message::<TcpStream, String>(&mut stream, false, Proto::TCP)
+                        .await
+                        .unwrap();
+                    let email = Email::from_json(&message.get_payload().await).unwrap();
+                    let key = email.idempotency_key.unwrap().to_string();
+
+                    let (status, stall) = {
+                        let mut mailbox = mailbox.lock().unwrap();
+                        mailbox.connections += 1;
+                        if always_error {
+                            (ProtocolStatus::ERROR, false)
+                        } else if !mailbox.keys.insert(key) {
+                            (MAIL_DUPLICATE, false)
+                        } else {
+                            mailbox.delivered += 1;
+                            (MAIL_ACCEPTED, mailbox.connections == 1)
+                        }
+                    };
+
+                    if stall {
+                        tokio::time::sleep(Duration::from_secs(30)).await;
+                    }
+                    let reply = create_response(status).await.unwrap();
+                    send_data(&mut stream, reply, Proto::TCP).await.unwrap();
+                });
+            }
+        });
+
+        (addr, mailbox)
+    }
+
+    fn email() -> Email {
+        Email::new(Stringy::from("Disk full"), Stringy::from("node-1 is at 100%"))
+    }
+
+    #[test]
+    fn idempotency_key_depends_on_every_part() {
+        let key = email().idempotency_key_for("mail:1827", "nonce");
+        assert_eq!(key, email().idempotency_key_for("mail:1827", "nonce"));
+        assert_ne!(key, email().idempotency_key_for("mail:1828", "nonce"));
+        assert_ne!(key, email().idempotency_key_for("mail:1827", "other"));
+        assert_ne!(
+            key,
+            Email::new(Stringy::from("Disk ful"), Stringy::from("lnode-1 is at 100%"))
+                .idempotency_key_for("mail:1827", "nonce")
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_lost_reply_is_not_delivered_twice() {
+        let (addr, mailbox) = spawn_mail_server(false).await;
+
+        let result = email()
+            .send_with_retry(Some(&addr), "nonce", 3, Duration::from_millis(10), Duration::from_millis(200))
+            .await;
+
+        assert!(result.is_ok());
+        let mailbox = mailbox.lock().unwrap();
+        assert_eq!(mailbox.connections, 2);
+        assert_eq!(mailbox.delivered, 1);
+    }
+
+    #[tokio::test]
+    async fn error_reply_is_a_failure() {
+        let (addr, mailbox) = spawn_mail_server(true).await;
+
+        let result = email()
+            .send_with_retry(Some(&addr), "nonce", 2, Duration::from_millis(10), Duration::from_millis(200))
+            .await;
+
+        assert!(!result.is_ok());
+        assert_eq!(mailbox.lock().unwrap().connections, 2);
+    }
 }
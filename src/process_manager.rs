@@ -2,13 +2,15 @@ use dusa_collection_utils::errors::Errors;
 use dusa_collection_utils::log;
 use dusa_collection_utils::log::LogLevel;
 use dusa_collection_utils::rwarc::LockWithTimeout;
-use libc::{c_int, kill, killpg, SIGKILL, SIGTERM};
-use nix::sys::wait::waitpid;
+use libc::{c_int, getpgid, getpgrp, kill, killpg, SIGKILL, SIGTERM};
+use nix::sys::wait::{waitpid, WaitPidFlag};
 use nix::unistd::Pid;
-use std::process::Stdio;
+use procfs::process::{all_processes, Process};
+use std::process::{ExitStatus, Stdio};
 use std::time::Duration;
-use std::{io, thread};
+use std::io;
 use tokio::process::{Child, Command};
+use tokio::task::{JoinHandle, JoinSet};
 
 use crate::aggregator::Metrics;
 use crate::resource_monitor::ResourceMonitorLock;
@@ -23,6 +25,9 @@ pub struct ChildLock(LockWithTimeout<Child>);
 pub struct SupervisedChild {
     pub child: ChildLock,
     pub monitor: ResourceMonitorLock,
+    /// The child's `/proc/<pid>/stat` starttime, recorded at spawn so a
+    /// recycled pid can be told apart from the process we started
+    pub starttime: Option<u64>,
 }
 
 impl SupervisedChild {
@@ -31,7 +36,7 @@ impl SupervisedChild {
     pub async fn new(command: Command) -> Result<Self, ErrorArrayItem> {
         let super_child = spawn_complex_process(command, true, true).await?;
         super_child.monitor_usage().await;
-        return Ok(super_child)
+        Ok(super_child)
     }
 
     pub async fn get_pid(&self) -> Result<u32, ErrorArrayItem> {
@@ -44,10 +49,10 @@ impl SupervisedChild {
             }
         };
 
-        return match child_data.id() {
+        match child_data.id() {
             Some(xid) => Ok(xid),
             None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid PID").into()),
-        };
+        }
     }
 
     pub async fn clone(&self) -> Self {
@@ -57,13 +62,49 @@ impl SupervisedChild {
         let monitor: ResourceMonitorLock = monitor_lock.clone();
         let child: ChildLock = child_lock.clone();
 
-        Self { child, monitor }
+        Self {
+            child,
+            monitor,
+            starttime: self.starttime,
+        }
     }
 
     pub async fn kill(&self) -> Result<(), ErrorArrayItem> {
         self.child.kill().await
     }
 
+    /// Checks the process behind our pid still has `expected_starttime`.
+    /// Returns `false` when the pid is gone or now belongs to another process.
+    pub async fn verify_identity(&self, expected_starttime: u64) -> Result<bool, ErrorArrayItem> {
+        let pid: u32 = self.get_pid().await?;
+        Ok(verify_process_identity(pid as i32, expected_starttime))
+    }
+
+    /// Like `kill_with_report`, but refuses to signal anything when the pid
+    /// no longer matches the starttime recorded at spawn.
+    pub async fn kill_verified(&self) -> Result<KillReport, ErrorArrayItem> {
+        let expected: u64 = self.starttime.ok_or_else(|| {
+            ErrorArrayItem::new(
+                Errors::GeneralError,
+                "No starttime was recorded for this child".to_owned(),
+            )
+        })?;
+
+        if !self.verify_identity(expected).await? {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Process behind the pid is not the one we spawned, refusing to signal it".to_owned(),
+            ));
+        }
+
+        self.kill_with_report().await
+    }
+
+    /// Like `kill`, but reports which pids survived so callers can escalate
+    pub async fn kill_with_report(&self) -> Result<KillReport, ErrorArrayItem> {
+        self.child.kill_with_report().await
+    }
+
     pub async fn running(&self) -> bool {
         let xid = match self.get_pid().await {
             Ok(xid) => xid,
@@ -73,6 +114,23 @@ impl SupervisedChild {
         ChildLock::running(xid.try_into().unwrap())
     }
 
+    /// Checks the whole process group rather than just the direct child.
+    /// Shell wrappers often fork the real workload and exit, so the
+    /// original pid can be gone while its descendants are still working.
+    pub async fn group_running(&self) -> bool {
+        let xid = match self.get_pid().await {
+            Ok(xid) => xid,
+            Err(_) => return false,
+        };
+
+        let pid: c_int = match xid.try_into() {
+            Ok(pid) => pid,
+            Err(_) => return false,
+        };
+
+        ChildLock::group_running(pid)
+    }
+
     /// Spawns a endless loop that updates the resource monitor from /proc
     pub async fn monitor_usage(&self) {
         let d0: &ResourceMonitorLock = &self.monitor;
@@ -83,11 +141,47 @@ impl SupervisedChild {
         self.monitor.get_metrics().await
     }
 
+    /// Collects the exit status of the child if it has exited on its own.
+    /// Returns `None` while the child is still running.
+    pub async fn reap(&self) -> Result<Option<ExitStatus>, ErrorArrayItem> {
+        self.child.reap().await
+    }
+
+    /// Spawns a background task that periodically reaps the child so
+    /// processes that exit without an explicit kill don't linger as zombies.
+    /// The task stops once the child has been reaped, or once it can no
+    /// longer be waited on (e.g. `ECHILD` after `kill_with_report` reaped it).
+    pub fn spawn_reaper(&self, interval: Duration) -> JoinHandle<()> {
+        let child: ChildLock = self.child.clone();
+        tokio::spawn(async move {
+            loop {
+                match child.reap().await {
+                    Ok(Some(status)) => {
+                        log!(LogLevel::Trace, "Reaped child, exited with: {}", status);
+                        break;
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        log!(LogLevel::Debug, "Stopped reaping child: {}", err);
+                        break;
+                    }
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
     // pub async fn check_usage(&self) {
     //     self.monitor.print_usage().await;
     // }
 }
 
+impl Clone for ChildLock {
+    fn clone(&self) -> Self {
+        ChildLock(self.0.clone())
+    }
+}
+
 impl ChildLock {
     pub fn new(child: Child) -> Self {
         let rw_lock: LockWithTimeout<Child> = LockWithTimeout::new(child);
@@ -96,64 +190,110 @@ impl ChildLock {
 
     pub fn update(mut self, new_child: Child) -> Self {
         self.0 = LockWithTimeout::new(new_child);
-        return self;
+        self
+    }
+
+    pub async fn kill(&self) -> Result<(), ErrorArrayItem> {
+        let report: KillReport = self.kill_with_report().await?;
+        if !report.all_terminated() {
+            log!(
+                LogLevel::Warn,
+                "Processes survived SIGKILL: {:?}",
+                report.survived
+            );
+        }
+        Ok(())
     }
 
-    pub fn clone(&self) -> Self {
-        let data = self;
-        let child = &data.0;
-        let lock_clone = child.clone();
-        let cloned_child_lock = ChildLock{0: lock_clone};
-        cloned_child_lock
+    /// Kills the child's process group (or only the child when it shares the
+    /// manager's group), escalating to SIGKILL for anything still alive after
+    /// a second, and reports which pids went away and which didn't.
+    pub async fn kill_with_report(&self) -> Result<KillReport, ErrorArrayItem> {
+        self.kill_with_report_using(is_alive).await
     }
 
-    pub async fn kill(&self) -> Result<(), ErrorArrayItem> {
-        let child = self.0.try_read().await?;
-
-        let xid = match child.id() {
-            Some(xid) => xid,
-            None => {
-                return Err(ErrorArrayItem::new(
-                    dusa_collection_utils::errors::Errors::InputOutput,
-                    "No xid provided".to_owned(),
-                ))
+    /// `kill_with_report` with the liveness check swapped out, so tests can
+    /// stand in for a process stuck in uninterruptible sleep
+    async fn kill_with_report_using<A>(&self, alive: A) -> Result<KillReport, ErrorArrayItem>
+    where
+        A: Fn(c_int) -> bool,
+    {
+        let xid = {
+            let child = self.0.try_read().await?;
+            match child.id() {
+                Some(xid) => xid,
+                None => {
+                    return Err(ErrorArrayItem::new(
+                        dusa_collection_utils::errors::Errors::InputOutput,
+                        "No xid provided".to_owned(),
+                    ))
+                }
             }
         };
 
-        // Kill the entire process group
-        unsafe {
-            // ! this will halt if the pid assigned is too long
-            let pgid = xid; // Since we set pgid to pid in pre_exec
-            killpg(pgid as i32, SIGTERM);
-            Self::reap_zombie_process(pgid.try_into().unwrap());
+        let pid: c_int = xid
+            .try_into()
+            .map_err(|_| ErrorArrayItem::from(io::Error::new(io::ErrorKind::InvalidInput, "Invalid PID")))?;
+
+        // Kill the entire process group, unless the child shares ours.
+        // Signalling that group would take the manager down with it
+        let own_group: bool = is_own_group(pid);
+        let mut targets: Vec<c_int> = match own_group {
+            true => {
+                log!(
+                    LogLevel::Warn,
+                    "Process with PID: {} shares the manager's process group, only signalling the process",
+                    pid
+                );
+                vec![pid]
+            }
+            false => group_members(pid), // Since we set pgid to pid in pre_exec
         };
+        if !targets.contains(&pid) {
+            targets.push(pid);
+        }
 
-        // Wait for a moment to see if the process terminates
-        thread::sleep(Duration::from_secs(1));
-
-        // If still running, force kill the process (send SIGKILL)
-        if let Ok(xid) = xid.try_into() {
-            match Self::running(xid) {
-                true => {
-                    log!(
-                        LogLevel::Warn,
-                        "Process with PID: {} did not terminate, sending SIGKILL",
-                        xid
-                    );
-                    unsafe {
-                        if kill(xid, SIGKILL) != 0 {
-                            return Err(io::Error::last_os_error().into());
-                        }
-                        Self::reap_zombie_process(xid);
-                        log!(LogLevel::Trace, "Process with PID: {} terminated", xid);
-                        return Ok(());
-                    }
+        unsafe {
+            match own_group {
+                true => kill(pid, SIGTERM),
+                false => killpg(pid, SIGTERM),
+            };
+        }
+
+        // Wait for a moment to see if the processes terminate
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Anything still running gets SIGKILL
+        let stubborn: Vec<c_int> = targets.iter().copied().filter(|pid| alive(*pid)).collect();
+        if !stubborn.is_empty() {
+            log!(
+                LogLevel::Warn,
+                "Processes {:?} did not terminate, sending SIGKILL",
+                stubborn
+            );
+            for pid in &stubborn {
+                unsafe {
+                    kill(*pid, SIGKILL);
                 }
-                false => return Ok(()),
             }
-        } else {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid PID").into());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        for pid in &targets {
+            Self::reap_zombie_process(*pid);
         }
+
+        let (survived, terminated): (Vec<c_int>, Vec<c_int>) =
+            targets.into_iter().partition(|pid| alive(*pid));
+
+        log!(LogLevel::Trace, "Processes {:?} terminated", terminated);
+        Ok(KillReport { terminated, survived })
+    }
+
+    /// Non blocking wait on the child, clearing it from the process table if it exited
+    pub async fn reap(&self) -> Result<Option<ExitStatus>, ErrorArrayItem> {
+        let mut child = self.0.try_write().await?;
+        child.try_wait().map_err(ErrorArrayItem::from)
     }
 
     /// Check if a process is running based on its PID
@@ -161,10 +301,199 @@ impl ChildLock {
         unsafe { kill(pid, 0) == 0 }
     }
 
-    /// Reap zombie processes to clean up system resources
+    /// Check if any process in the group is still running. Since children
+    /// spawned with `setsid` lead their own group the pgid is the child's pid.
+    /// Zombies don't count, an exited leader waiting to be reaped would
+    /// otherwise keep the group looking alive.
+    pub fn group_running(pgid: c_int) -> bool {
+        !group_members(pgid).is_empty()
+    }
+
+    /// Reap zombie processes to clean up system resources. Doesn't block,
+    /// a process that hasn't exited yet is left alone
     fn reap_zombie_process(pid: c_int) {
-        let _ = waitpid(Pid::from_raw(pid), None);
+        let _ = waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG));
+    }
+}
+
+/// Which processes a kill took down and which were still alive afterwards
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KillReport {
+    pub terminated: Vec<i32>,
+    pub survived: Vec<i32>,
+}
+
+impl KillReport {
+    pub fn all_terminated(&self) -> bool {
+        self.survived.is_empty()
+    }
+}
+
+/// Every live process whose process group is `pgid`
+fn group_members(pgid: c_int) -> Vec<c_int> {
+    let processes = match all_processes() {
+        Ok(processes) => processes,
+        Err(err) => {
+            log!(LogLevel::Warn, "Failed to list processes: {}", err);
+            return Vec::new();
+        }
+    };
+
+    processes
+        .filter_map(|process| process.ok())
+        .filter_map(|process| process.stat().ok())
+        .filter(|stat| stat.pgrp == pgid && stat.state != 'Z')
+        .map(|stat| stat.pid)
+        .collect()
+}
+
+/// Whether `pid` exists and isn't a zombie waiting to be reaped
+fn is_alive(pid: c_int) -> bool {
+    match Process::new(pid).and_then(|process| process.stat()) {
+        Ok(stat) => stat.state != 'Z',
+        Err(_) => false,
+    }
+}
+
+/// Reads the starttime of `pid` from `/proc/<pid>/stat`, in clock ticks since boot
+pub fn process_starttime(pid: i32) -> Result<u64, ErrorArrayItem> {
+    Process::new(pid)
+        .and_then(|process| process.stat())
+        .map(|stat| stat.starttime)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+}
+
+/// True when `pid` is alive and started at `expected_starttime`, i.e. it is
+/// the same process and not a new one that reused the number
+pub fn verify_process_identity(pid: i32, expected_starttime: u64) -> bool {
+    match process_starttime(pid) {
+        Ok(starttime) => starttime == expected_starttime && is_alive(pid),
+        Err(_) => false,
+    }
+}
+
+/// Check if `pid` is in the same process group as the manager.
+/// Returns `false` if the pid doesn't exist.
+pub fn is_own_group(pid: i32) -> bool {
+    unsafe {
+        let pgid = getpgid(pid);
+        pgid != -1 && pgid == getpgrp()
+    }
+}
+
+/// Reaps every child in the list that has exited, returning how many have exited.
+/// Long lived managers should call this periodically when they rely on
+/// children exiting on their own rather than being killed.
+pub async fn reap_children(children: &[SupervisedChild]) -> usize {
+    let mut reaped: usize = 0;
+    for child in children {
+        match child.reap().await {
+            Ok(Some(_)) => reaped += 1,
+            Ok(None) => (),
+            Err(err) => log!(LogLevel::Warn, "Failed to reap child: {}", err),
+        }
+    }
+    reaped
+}
+
+/// Collects the metrics of every child concurrently, returned in the same order
+/// as `children`. A child whose pid can't be read is reported with pid `0` and
+/// the error, one failing child doesn't stop the sweep.
+pub async fn collect_metrics(children: &[SupervisedChild]) -> Vec<(u32, Result<Metrics, ErrorArrayItem>)> {
+    let mut tasks: JoinSet<(usize, u32, Result<Metrics, ErrorArrayItem>)> = JoinSet::new();
+
+    for (index, child) in children.iter().enumerate() {
+        let child: SupervisedChild = child.clone().await;
+        tasks.spawn(async move {
+            match child.get_pid().await {
+                Ok(pid) => (index, pid, child.get_metrics().await),
+                Err(err) => (index, 0, Err(err)),
+            }
+        });
+    }
+
+    let mut results: Vec<Option<(u32, Result<Metrics, ErrorArrayItem>)>> =
+        (0..children.len()).map(|_| None).collect();
+
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((index, pid, metrics)) => results[index] = Some((pid, metrics)),
+            Err(err) => log!(LogLevel::Error, "Metrics task failed: {}", err),
+        }
     }
+
+    results
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|| {
+                (
+                    0,
+                    Err(ErrorArrayItem::new(
+                        Errors::GeneralError,
+                        "Metrics task didn't complete".to_owned(),
+                    )),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Env var spawned processes read their log level from
+pub const LOG_LEVEL_ENV: &str = "ARTISAN_LOG_LEVEL";
+
+/// Sets `ARTISAN_LOG_LEVEL` and `RUST_LOG` on the command so the child logs at `level`
+pub fn apply_log_level(command: &mut Command, level: LogLevel) {
+    let level: &str = match level {
+        LogLevel::Error => "error",
+        LogLevel::Warn => "warn",
+        LogLevel::Info => "info",
+        LogLevel::Debug => "debug",
+        LogLevel::Trace => "trace",
+    };
+    command.env(LOG_LEVEL_ENV, level);
+    command.env("RUST_LOG", level);
+}
+
+/// A runner oom_score_adj that makes the kernel pick runners well before the manager
+pub const RUNNER_OOM_SCORE_ADJ: i16 = 500;
+
+/// Sets the child's `oom_score_adj` before it execs, clamped to -1000..=1000.
+///
+/// Positive values make the child a preferred OOM victim so a runaway
+/// runner is killed instead of the manager. Lowering the score below the
+/// parent's needs CAP_SYS_RESOURCE, without it the spawn fails.
+pub fn apply_oom_score_adj(command: &mut Command, adj: i16) {
+    let value: Vec<u8> = adj.clamp(-1000, 1000).to_string().into_bytes();
+    unsafe {
+        command.pre_exec(move || {
+            let fd: libc::c_int = libc::open(
+                c"/proc/self/oom_score_adj".as_ptr(),
+                libc::O_WRONLY | libc::O_CLOEXEC,
+            );
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let written: isize = libc::write(fd, value.as_ptr().cast(), value.len());
+            let write_error: io::Error = io::Error::last_os_error();
+            libc::close(fd);
+            if written == -1 {
+                return Err(write_error);
+            }
+            Ok(())
+        })
+    };
+}
+
+/// Spawns the app's process like `spawn_complex_process`, passing down the
+/// app's log level so a single runner can be turned up without touching the rest.
+pub async fn spawn_complex_process_for(
+    state: &AppState,
+    mut command: Command,
+    independent_process_group: bool,
+    capture_output: bool,
+) -> Result<SupervisedChild, ErrorArrayItem> {
+    apply_log_level(&mut command, state.effective_log_level());
+    spawn_complex_process(command, independent_process_group, capture_output).await
 }
 
 /// Spawn an asynchronous process, similar to the `create_child` logic
@@ -287,7 +616,13 @@ pub async fn spawn_complex_process(
             //  Creating the rw_lock for the child
             let child: ChildLock = ChildLock::new(child);
 
-            let supervised_child: SupervisedChild = SupervisedChild { child, monitor };
+            let starttime: Option<u64> = process_starttime(pid as i32).ok();
+
+            let supervised_child: SupervisedChild = SupervisedChild {
+                child,
+                monitor,
+                starttime,
+            };
 
             Ok(supervised_child)
         }
@@ -298,7 +633,286 @@ pub async fn spawn_complex_process(
                 error
             );
 
-            return Err(ErrorArrayItem::from(error));
+            Err(ErrorArrayItem::from(error))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, Environment, StateLocation};
+    use crate::encryption::EncryptionMode;
+    use dusa_collection_utils::version::SoftwareVersion;
+    use std::collections::HashMap;
+
+    fn zombie_or_gone(pid: u32) -> bool {
+        match Process::new(pid as i32).and_then(|process| process.stat()) {
+            Ok(stat) => stat.state == 'Z',
+            Err(_) => true,
+        }
+    }
+
+    #[tokio::test]
+    async fn reaper_collects_exited_child() {
+        let child = spawn_complex_process(Command::new("true"), true, false).await.unwrap();
+        let pid: u32 = child.get_pid().await.unwrap();
+
+        let reaper = child.spawn_reaper(Duration::from_millis(10));
+        tokio::time::timeout(Duration::from_secs(5), reaper)
+            .await
+            .expect("reaper didn't stop after the child exited")
+            .unwrap();
+
+        assert!(Process::new(pid as i32).is_err(), "child was left as a zombie");
+    }
+
+    #[tokio::test]
+    async fn reaper_stops_after_child_was_reaped_elsewhere() {
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        let child = spawn_complex_process(command, true, false).await.unwrap();
+        let pid: u32 = child.get_pid().await.unwrap();
+
+        child.kill_with_report().await.unwrap();
+        assert!(zombie_or_gone(pid));
+
+        let reaper = child.spawn_reaper(Duration::from_millis(10));
+        tokio::time::timeout(Duration::from_secs(5), reaper)
+            .await
+            .expect("reaper kept polling a child that was already reaped")
+            .unwrap();
+    }
+
+    /// Spawns `script` through `sh` in its own process group
+    async fn spawn_shell(script: &str) -> SupervisedChild {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        spawn_complex_process(command, true, false).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn group_with_only_a_zombie_leader_is_not_running() {
+        let child = spawn_shell("exit 0").await;
+        let pid: u32 = child.get_pid().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert!(zombie_or_gone(pid));
+        assert!(!child.group_running().await);
+    }
+
+    #[tokio::test]
+    async fn grandchild_keeps_the_group_running() {
+        let child = spawn_shell("sleep 30 & exit 0").await;
+        let pgid: c_int = child.get_pid().await.unwrap() as c_int;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // The shell is gone but the sleep it forked is still in the group
+        assert!(child.group_running().await);
+
+        unsafe { killpg(pgid, SIGKILL) };
+        let mut running: bool = true;
+        for _ in 0..50 {
+            running = child.group_running().await;
+            if !running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        assert!(!running);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_collected_for_every_child() {
+        let mut children: Vec<SupervisedChild> = Vec::new();
+        for _ in 0..3 {
+            children.push(spawn_shell("sleep 30").await);
+        }
+        let mut pids: Vec<u32> = Vec::new();
+        for child in &children {
+            pids.push(child.get_pid().await.unwrap());
+        }
+
+        let results = collect_metrics(&children).await;
+
+        assert_eq!(results.len(), 3);
+        for ((pid, metrics), expected) in results.iter().zip(&pids) {
+            assert_eq!(pid, expected);
+            assert!(metrics.is_ok());
+        }
+
+        for child in &children {
+            child.kill_with_report().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn own_group_is_told_apart_from_independent_children() {
+        assert!(is_own_group(std::process::id() as i32));
+        assert!(!is_own_group(-1));
+
+        let independent = spawn_shell("sleep 30").await;
+        let independent_pid = independent.get_pid().await.unwrap() as i32;
+        assert!(!is_own_group(independent_pid));
+
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        let shared = spawn_complex_process(command, false, false).await.unwrap();
+        let shared_pid = shared.get_pid().await.unwrap() as i32;
+        assert!(is_own_group(shared_pid));
+
+        // Killing a child in our group only signals the child itself
+        let report = shared.kill_with_report().await.unwrap();
+        assert_eq!(report.terminated, vec![shared_pid]);
+        assert!(report.survived.is_empty());
+
+        independent.kill_with_report().await.unwrap();
+    }
+
+    fn state(log_level_override: Option<LogLevel>) -> AppState {
+        AppState {
+            name: String::from("runner"),
+            version: SoftwareVersion::dummy(),
+            data: String::new(),
+            last_updated: 0,
+            event_counter: 0,
+            is_active: true,
+            error_log: vec![],
+            config: AppConfig {
+                app_name: "runner".into(),
+                version: String::from("1.0.0"),
+                max_ram_usage: 16,
+                max_cpu_usage: 16,
+                environment: Environment::Production,
+                debug_mode: false,
+                log_level: LogLevel::Info,
+                git: None,
+                database: None,
+                aggregator: None,
+                state_location: StateLocation::Tmp,
+                encryption: EncryptionMode::Recs,
+            },
+            system_application: false,
+            log_level_override,
+        }
+    }
+
+    async fn child_env(state: &AppState) -> HashMap<String, String> {
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        let child = spawn_complex_process_for(state, command, true, false).await.unwrap();
+        let pid = child.get_pid().await.unwrap() as i32;
+
+        let environ = Process::new(pid).unwrap().environ().unwrap();
+        child.kill_with_report().await.unwrap();
+        environ
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    key.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn child_inherits_the_log_level_override() {
+        let env = child_env(&state(Some(LogLevel::Debug))).await;
+        assert_eq!(env.get(LOG_LEVEL_ENV).map(String::as_str), Some("debug"));
+        assert_eq!(env.get("RUST_LOG").map(String::as_str), Some("debug"));
+
+        let env = child_env(&state(None)).await;
+        assert_eq!(env.get(LOG_LEVEL_ENV).map(String::as_str), Some("info"));
+    }
+
+    /// Spawns a shell that forks a grandchild, returning the child and the
+    /// grandchild's pid once both are in the group
+    async fn spawn_with_grandchild() -> (SupervisedChild, c_int) {
+        let child = spawn_shell("sleep 30 & wait").await;
+        let pgid: c_int = child.get_pid().await.unwrap() as c_int;
+        for _ in 0..50 {
+            if let Some(grandchild) = group_members(pgid).into_iter().find(|pid| *pid != pgid) {
+                return (child, grandchild);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("grandchild never started");
+    }
+
+    #[tokio::test]
+    async fn kill_reports_the_whole_group_terminated() {
+        let (child, grandchild) = spawn_with_grandchild().await;
+        let pid: c_int = child.get_pid().await.unwrap() as c_int;
+
+        let report = child.kill_with_report().await.unwrap();
+
+        assert!(report.all_terminated());
+        assert!(report.terminated.contains(&pid));
+        assert!(report.terminated.contains(&grandchild));
+    }
+
+    #[tokio::test]
+    async fn process_surviving_sigkill_is_reported() {
+        let (child, stuck) = spawn_with_grandchild().await;
+        let pid: c_int = child.get_pid().await.unwrap() as c_int;
+
+        // Stands in for a process in uninterruptible sleep that ignores SIGKILL
+        let report = child
+            .child
+            .kill_with_report_using(|candidate| candidate == stuck || is_alive(candidate))
+            .await
+            .unwrap();
+
+        assert!(!report.all_terminated());
+        assert_eq!(report.survived, vec![stuck]);
+        assert_eq!(report.terminated, vec![pid]);
+    }
+
+    async fn child_oom_score_adj(adj: Option<i16>) -> i32 {
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        if let Some(adj) = adj {
+            apply_oom_score_adj(&mut command, adj);
+        }
+        let child = spawn_complex_process(command, true, false).await.unwrap();
+        let pid = child.get_pid().await.unwrap();
+
+        let value = std::fs::read_to_string(format!("/proc/{}/oom_score_adj", pid)).unwrap();
+        child.kill_with_report().await.unwrap();
+        value.trim().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn runner_gets_the_oom_score_adj() {
+        let own: i32 = std::fs::read_to_string("/proc/self/oom_score_adj")
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+
+        assert_eq!(child_oom_score_adj(Some(RUNNER_OOM_SCORE_ADJ)).await, 500);
+        assert_eq!(child_oom_score_adj(Some(5000)).await, 1000);
+        assert_eq!(child_oom_score_adj(None).await, own);
+    }
+
+    #[tokio::test]
+    async fn recycled_pid_is_detected_and_not_signalled() {
+        let mut child = spawn_shell("sleep 30").await;
+        let pid: i32 = child.get_pid().await.unwrap() as i32;
+        let starttime: u64 = child.starttime.expect("starttime wasn't recorded at spawn");
+
+        assert_eq!(process_starttime(pid).unwrap(), starttime);
+        assert!(child.verify_identity(starttime).await.unwrap());
+
+        // A different starttime behind the same pid is what a reused pid looks like
+        child.starttime = Some(starttime + 1);
+        assert!(!child.verify_identity(starttime + 1).await.unwrap());
+        assert!(child.kill_verified().await.is_err());
+        assert!(is_alive(pid));
+
+        child.starttime = Some(starttime);
+        assert!(child.kill_verified().await.unwrap().all_terminated());
+        assert!(!verify_process_identity(pid, starttime));
     }
 }
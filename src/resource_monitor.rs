@@ -8,11 +8,13 @@ use dusa_collection_utils::{
 };
 use gethostname::gethostname;
 use procfs::process::{all_processes, Process};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
+    fmt,
     fs::File,
     io::{self, Read},
-    thread,
     time::Duration,
 };
 use sysinfo::System;
@@ -21,6 +23,12 @@ use crate::aggregator::Metrics;
 
 pub struct ResourceMonitorLock(LockWithTimeout<ResourceMonitor>);
 
+impl Clone for ResourceMonitorLock {
+    fn clone(&self) -> Self {
+        ResourceMonitorLock(self.0.clone())
+    }
+}
+
 impl ResourceMonitorLock {
     pub fn new(pid: i32) -> Result<Self, Box<dyn std::error::Error>> {
         let resource_monitor: ResourceMonitor = ResourceMonitor::new(pid)?;
@@ -30,6 +38,13 @@ impl ResourceMonitorLock {
     }
 
     pub async fn monitor(&self, delay: u64) {
+        self.monitor_with_jitter(delay, 0).await
+    }
+
+    /// Like `monitor`, but each interval is randomly stretched or shrunk by up
+    /// to `jitter_percent` percent so monitors started together drift apart
+    /// instead of sampling in lockstep. A jitter of 0 keeps the interval fixed.
+    pub async fn monitor_with_jitter(&self, delay: u64, jitter_percent: u8) {
         let new_monitor_lock: ResourceMonitorLock = self.clone();
         tokio::spawn(async move {
             loop {
@@ -58,23 +73,22 @@ impl ResourceMonitorLock {
                 drop(monitor_lock);
                 log!(LogLevel::Trace, "Process monitor updated information");
 
-                thread::sleep(Duration::from_secs(delay));
+                tokio::time::sleep(jittered(Duration::from_secs(delay), jitter_percent)).await;
             }
         });
     }
 
-    pub fn clone(&self) -> Self {
-        let data = self;
-        let cloned_data = data.0.clone();
-        return ResourceMonitorLock(cloned_data);
-    }
-
     pub async fn print_usage(&self) {
         let d0 = self.0.try_read().await.unwrap();
         println!("ram: {}", d0.ram);
         println!("cpu: {}", d0.cpu);
     }
 
+    pub async fn detailed_memory(&self) -> Result<MemoryBreakdown, ErrorArrayItem> {
+        let child_data = self.0.try_read().await?;
+        child_data.detailed_memory()
+    }
+
     pub async fn get_metrics(&self) -> Result<Metrics, ErrorArrayItem> {
         let child_data = self.0.try_read().await?;
         Ok(Metrics {
@@ -85,6 +99,53 @@ impl ResourceMonitorLock {
     }
 }
 
+/// Randomly offsets `interval` by up to `jitter_percent` percent either way.
+/// `jitter_percent` is capped at 100.
+pub fn jittered(interval: Duration, jitter_percent: u8) -> Duration {
+    let jitter_percent: u32 = jitter_percent.min(100) as u32;
+    if jitter_percent == 0 {
+        return interval;
+    }
+
+    let spread: Duration = interval * jitter_percent / 100;
+    let offset: Duration = spread.mul_f64(rand::thread_rng().gen_range(0.0..=1.0));
+
+    match rand::thread_rng().gen_bool(0.5) {
+        true => interval + offset,
+        false => interval.saturating_sub(offset),
+    }
+}
+
+/// Memory usage of a single process as reported by `/proc/<pid>/status`.
+/// All values are in kB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryBreakdown {
+    /// Resident set size (VmRSS)
+    pub rss_kb: u64,
+    /// Virtual memory size (VmSize)
+    pub vsz_kb: u64,
+    /// Swapped out memory (VmSwap)
+    pub swap_kb: u64,
+    /// Resident memory backed by files or shared memory (RssFile + RssShmem)
+    pub shared_kb: u64,
+}
+
+impl MemoryBreakdown {
+    pub fn rss_mb(&self) -> f32 {
+        self.rss_kb as f32 / 1024.0
+    }
+}
+
+impl fmt::Display for MemoryBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RSS: {} kB, VSZ: {} kB, Swap: {} kB, Shared: {} kB",
+            self.rss_kb, self.vsz_kb, self.swap_kb, self.shared_kb
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct ResourceMonitor {
     pub pid: i32,
@@ -143,6 +204,26 @@ impl ResourceMonitor {
         Ok((cpu_usage, memory))
     }
 
+    /// Reads the RSS, VSZ, swap and shared memory of the monitored process
+    pub fn detailed_memory(&self) -> Result<MemoryBreakdown, ErrorArrayItem> {
+        let process = Process::new(self.pid)
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+        Self::get_memory_breakdown(&process)
+    }
+
+    pub fn get_memory_breakdown(process: &Process) -> Result<MemoryBreakdown, ErrorArrayItem> {
+        let status = process
+            .status()
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+        Ok(MemoryBreakdown {
+            rss_kb: status.vmrss.unwrap_or(0),
+            vsz_kb: status.vmsize.unwrap_or(0),
+            swap_kb: status.vmswap.unwrap_or(0),
+            shared_kb: status.rssfile.unwrap_or(0) + status.rssshmem.unwrap_or(0),
+        })
+    }
+
     pub fn calculate_cpu_usage(
         stat: &procfs::process::Stat,
     ) -> Result<f32, Box<dyn std::error::Error>> {
@@ -288,3 +369,52 @@ pub fn get_system_stats() -> HashMap<Stringy, Stringy> {
 
     stats
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_breakdown_is_populated() {
+        let pid = std::process::id() as i32;
+        let breakdown = ResourceMonitor::new(pid).unwrap().detailed_memory().unwrap();
+
+        assert!(breakdown.rss_kb > 0);
+        assert!(breakdown.vsz_kb > 0);
+        assert!(breakdown.rss_kb <= breakdown.vsz_kb);
+        assert!(breakdown.shared_kb <= breakdown.rss_kb);
+
+        let locked = ResourceMonitorLock::new(pid).unwrap().detailed_memory().await.unwrap();
+        assert!(locked.rss_kb > 0);
+        assert!(locked.rss_kb <= locked.vsz_kb);
+    }
+
+    #[test]
+    fn jitter_off_keeps_the_interval() {
+        let interval = Duration::from_secs(300);
+        for _ in 0..100 {
+            assert_eq!(jittered(interval, 0), interval);
+        }
+    }
+
+    #[test]
+    fn jittered_tasks_drift_apart_within_bounds() {
+        let interval = Duration::from_secs(300);
+        let schedule = || {
+            let mut at = Duration::ZERO;
+            (0..20)
+                .map(|_| {
+                    let step = jittered(interval, 10);
+                    assert!(step >= Duration::from_secs(270) && step <= Duration::from_secs(330));
+                    at += step;
+                    at
+                })
+                .collect::<Vec<Duration>>()
+        };
+
+        // Two tasks started together no longer fire at the same moments
+        let first = schedule();
+        let second = schedule();
+        assert!(first.iter().zip(&second).all(|(a, b)| a != b));
+    }
+}
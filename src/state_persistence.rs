@@ -1,16 +1,17 @@
 use colored::Colorize;
 use dusa_collection_utils::version::SoftwareVersion;
 use serde::{Deserialize, Serialize};
-use std::{fmt, fs};
+use std::{fmt, fs, io};
 
+use dusa_collection_utils::log::LogLevel;
 use dusa_collection_utils::types::PathType;
-use dusa_collection_utils::{errors::ErrorArrayItem, stringy::Stringy};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
 
 use crate::git_actions::GitServer;
 use crate::timestamp::format_unix_timestamp;
 use crate::{
     config::AppConfig,
-    encryption::{decrypt_text, encrypt_text},
+    encryption::{decrypt_expecting_with_mode, encrypt_with_mode, ContentType, EncryptionMode},
 };
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -42,6 +43,17 @@ pub struct AppState {
 
     // Is a system application vs a client application
     pub system_application: bool,
+
+    // Log level for this app's spawned processes, overriding config.log_level
+    #[serde(default)]
+    pub log_level_override: Option<LogLevel>,
+}
+
+impl AppState {
+    /// The log level spawned processes should run with
+    pub fn effective_log_level(&self) -> LogLevel {
+        self.log_level_override.unwrap_or(self.config.log_level)
+    }
 }
 
 impl fmt::Display for AppState {
@@ -157,7 +169,7 @@ impl fmt::Display for AppState {
 
         if let Some(database) = &self.config.database {
             writeln!(f, "    {}:", "Database Configuration".bold().purple())?;
-            writeln!(f, "      {}: {}", "URL".bold().cyan(), database.url)?;
+            writeln!(f, "      {}: {}", "URL".bold().cyan(), database.redacted_url())?;
             writeln!(
                 f,
                 "      {}: {}",
@@ -195,25 +207,304 @@ impl fmt::Display for AppState {
 pub struct StatePersistence;
 
 impl StatePersistence {
+    /// Where the app's state file lives, following `config.state_location`
     pub fn get_state_path(config: &AppConfig) -> PathType {
-        PathType::Content(format!("/tmp/.{}.state", config.app_name))
+        config.state_location.state_path(&config.app_name)
     }
 
+    /// Saves the state, encrypted unless `state.config.encryption` is `EncryptionMode::None`.
+    ///
+    /// A plaintext state is refused by `load_state`; read it back with
+    /// `load_state_with_mode(path, EncryptionMode::None)`.
     pub async fn save_state(state: &AppState, path: &PathType) -> Result<(), Box<dyn std::error::Error>> {
-        let toml_str: Stringy = toml::to_string(state)?.into();
-        let state_data = encrypt_text(toml_str)
-            .await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.err_mesg.to_string()))?;
-        fs::write(path, state_data.to_string())?;
-        Ok(())
+        Ok(Self::try_save(state, path).await?)
     }
 
+    /// Loads an encrypted state, refusing plaintext ones.
+    ///
+    /// States saved with `EncryptionMode::None` fail with a "Refusing to load a
+    /// plaintext envelope" error here, whatever the app's config says. Use
+    /// `load_state_with_mode` with the configured mode to load them.
     pub async fn load_state(path: &PathType) -> Result<AppState, Box<dyn std::error::Error>> {
-        let encrypted_content: Stringy = fs::read_to_string(path)?.into();
-        let content: Stringy = decrypt_text(encrypted_content).await.map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Decryption failed")
-        })?;
-        let state: AppState = toml::from_str(&content)?;
+        Ok(Self::try_load(path).await?)
+    }
+
+    /// Loads a state, accepting plaintext ones only when `mode` is `EncryptionMode::None`
+    pub async fn load_state_with_mode(
+        path: &PathType,
+        mode: EncryptionMode,
+    ) -> Result<AppState, Box<dyn std::error::Error>> {
+        Ok(Self::try_load_with_mode(path, mode).await?)
+    }
+
+    /// Saves the state, reporting which step failed as a `PersistenceError`
+    pub async fn try_save(state: &AppState, path: &PathType) -> Result<(), PersistenceError> {
+        let toml_str: String =
+            toml::to_string(state).map_err(|e| PersistenceError::Serde(e.to_string()))?;
+        let state_data: Vec<u8> =
+            encrypt_with_mode(toml_str.as_bytes(), ContentType::AppState, state.config.encryption)
+                .await
+                .map_err(|e| PersistenceError::Encrypt(e.err_mesg.to_string()))?;
+        fs::write(path, state_data)?;
+        Ok(())
+    }
+
+    /// Loads the state, reporting which step failed as a `PersistenceError`.
+    /// Like `load_state` it refuses plaintext; see `try_load_with_mode`.
+    pub async fn try_load(path: &PathType) -> Result<AppState, PersistenceError> {
+        Self::try_load_with_mode(path, EncryptionMode::Recs).await
+    }
+
+    /// Like `try_load`, accepting plaintext states only when `mode` is `EncryptionMode::None`
+    pub async fn try_load_with_mode(
+        path: &PathType,
+        mode: EncryptionMode,
+    ) -> Result<AppState, PersistenceError> {
+        let encrypted_content: Vec<u8> = fs::read(path)?;
+        let decrypted: Vec<u8> = decrypt_expecting_with_mode(
+            encrypted_content.trim_ascii_end(),
+            ContentType::AppState,
+            mode,
+        )
+        .await
+        .map_err(|e| PersistenceError::Decrypt(e.err_mesg.to_string()))?;
+        let content: String =
+            String::from_utf8(decrypted).map_err(|e| PersistenceError::Decrypt(e.to_string()))?;
+        let state: AppState =
+            toml::from_str(&content).map_err(|e| PersistenceError::Serde(e.to_string()))?;
         Ok(state)
     }
 }
+
+/// Why saving or loading an encrypted file failed.
+/// Shared by the `try_save`/`try_load` helpers for state, identity and git credentials.
+#[derive(Debug)]
+pub enum PersistenceError {
+    /// The data couldn't be encrypted
+    Encrypt(String),
+    /// The file was read but couldn't be decrypted
+    Decrypt(String),
+    /// Reading or writing the file failed
+    Io(io::Error),
+    /// The data couldn't be serialized or the decrypted data couldn't be parsed
+    Serde(String),
+}
+
+impl fmt::Display for PersistenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistenceError::Encrypt(msg) => write!(f, "Encryption failed: {}", msg),
+            PersistenceError::Decrypt(msg) => write!(f, "Decryption failed: {}", msg),
+            PersistenceError::Io(err) => write!(f, "I/O error: {}", err),
+            PersistenceError::Serde(msg) => write!(f, "Serialization error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PersistenceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistenceError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PersistenceError {
+    fn from(err: io::Error) -> Self {
+        PersistenceError::Io(err)
+    }
+}
+
+impl From<PersistenceError> for ErrorArrayItem {
+    fn from(err: PersistenceError) -> Self {
+        let kind: Errors = match &err {
+            PersistenceError::Encrypt(_) | PersistenceError::Decrypt(_) => Errors::GeneralError,
+            PersistenceError::Io(_) => Errors::InputOutput,
+            PersistenceError::Serde(_) => Errors::JsonReading,
+        };
+        ErrorArrayItem::new(kind, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Environment, StateLocation};
+    use crate::encryption::{encrypt_tagged, encrypt_text, MAX_ENVELOPE_PAYLOAD};
+    use crate::git_actions::{GitAuth, GitCredentials};
+    use dusa_collection_utils::stringy::Stringy;
+    use tempfile::TempDir;
+
+    fn state() -> AppState {
+        AppState {
+            name: String::from("state_test"),
+            version: SoftwareVersion::dummy(),
+            data: String::from("data"),
+            last_updated: 1,
+            event_counter: 2,
+            is_active: true,
+            error_log: vec![],
+            config: AppConfig {
+                app_name: "state_test".into(),
+                version: String::from("1.0.0"),
+                max_ram_usage: 16,
+                max_cpu_usage: 16,
+                environment: Environment::Development,
+                debug_mode: false,
+                log_level: LogLevel::Info,
+                git: None,
+                database: None,
+                aggregator: None,
+                state_location: StateLocation::Tmp,
+                encryption: EncryptionMode::Recs,
+            },
+            system_application: false,
+            log_level_override: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn state_round_trips() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+
+        StatePersistence::try_save(&state(), &path).await.unwrap();
+        assert_eq!(StatePersistence::try_load(&path).await.unwrap(), state());
+    }
+
+    #[tokio::test]
+    async fn credentials_blob_is_not_loaded_as_state() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+        let credentials = GitCredentials {
+            auth_items: vec![GitAuth {
+                user: Stringy::from("user"),
+                repo: Stringy::from("repo"),
+                branch: Stringy::from("main"),
+                server: GitServer::GitHub,
+                token: None,
+            }],
+        };
+        credentials.try_save(&path).await.unwrap();
+
+        let err = StatePersistence::try_load(&path).await.unwrap_err();
+        assert!(matches!(err, PersistenceError::Decrypt(_)));
+        assert!(err
+            .to_string()
+            .contains("Content type mismatch: expected AppState but found GitCredentials"));
+    }
+
+    #[tokio::test]
+    async fn untagged_state_still_loads() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+        let legacy = encrypt_text(Stringy::from(toml::to_string(&state()).unwrap())).await.unwrap();
+        fs::write(&path, legacy.to_string()).unwrap();
+
+        assert_eq!(StatePersistence::try_load(&path).await.unwrap(), state());
+    }
+
+    #[tokio::test]
+    async fn plaintext_state_round_trips_only_in_none_mode() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+        let mut state = state();
+        state.config.encryption = EncryptionMode::None;
+
+        StatePersistence::save_state(&state, &path).await.unwrap();
+        let stored: Vec<u8> = fs::read(&path).unwrap();
+        assert!(stored.starts_with(b"AHP"));
+
+        let loaded = StatePersistence::load_state_with_mode(&path, EncryptionMode::None)
+            .await
+            .unwrap();
+        assert_eq!(loaded, state);
+
+        // The format tag keeps a plaintext file from being taken for ciphertext
+        let err = StatePersistence::try_load(&path).await.unwrap_err();
+        assert!(err.to_string().contains("plaintext envelope"));
+    }
+
+    #[tokio::test]
+    async fn load_state_refuses_a_state_saved_in_none_mode() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+        let mut state = state();
+        state.config.encryption = EncryptionMode::None;
+
+        StatePersistence::save_state(&state, &path).await.unwrap();
+
+        let err = StatePersistence::load_state(&path).await.unwrap_err();
+        assert!(err.to_string().contains("Refusing to load a plaintext envelope"));
+    }
+
+    #[tokio::test]
+    async fn encrypted_state_loads_in_none_mode() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+
+        StatePersistence::save_state(&state(), &path).await.unwrap();
+        assert!(!fs::read(&path).unwrap().starts_with(b"AHP"));
+
+        let loaded = StatePersistence::load_state_with_mode(&path, EncryptionMode::None)
+            .await
+            .unwrap();
+        assert_eq!(loaded, state());
+    }
+
+    #[tokio::test]
+    async fn io_failures_are_io_errors() {
+        let dir = TempDir::new().unwrap();
+        let missing = PathType::PathBuf(dir.path().join("missing.state"));
+        let no_dir = PathType::PathBuf(dir.path().join("missing").join("app.state"));
+
+        assert!(matches!(
+            StatePersistence::try_load(&missing).await,
+            Err(PersistenceError::Io(_))
+        ));
+        assert!(matches!(
+            StatePersistence::try_save(&state(), &no_dir).await,
+            Err(PersistenceError::Io(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn undecryptable_file_is_a_decrypt_error() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+        fs::write(&path, "not encrypted").unwrap();
+
+        assert!(matches!(
+            StatePersistence::try_load(&path).await,
+            Err(PersistenceError::Decrypt(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unparsable_state_is_a_serde_error() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+        let encrypted = encrypt_tagged(b"not = [toml", ContentType::AppState).await.unwrap();
+        fs::write(&path, encrypted).unwrap();
+
+        assert!(matches!(
+            StatePersistence::try_load(&path).await,
+            Err(PersistenceError::Serde(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unencryptable_state_is_an_encrypt_error() {
+        let dir = TempDir::new().unwrap();
+        let path = PathType::PathBuf(dir.path().join("app.state"));
+        let mut state = state();
+        state.data = "a".repeat(MAX_ENVELOPE_PAYLOAD + 1);
+
+        assert!(matches!(
+            StatePersistence::try_save(&state, &path).await,
+            Err(PersistenceError::Encrypt(_))
+        ));
+        assert!(!path.exists());
+    }
+}
@@ -38,7 +38,7 @@ impl SystemdService {
                     service_name: Stringy::Immutable(service_name.into()),
                 })
             },
-            false => return Err(io::Error::new(io::ErrorKind::NotFound, format!{"{} not found", service_name})),
+            false => Err(io::Error::new(io::ErrorKind::NotFound, format!{"{} not found", service_name})),
         }
     }
 
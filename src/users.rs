@@ -1,10 +1,14 @@
-use std::{fs, os::unix::fs::PermissionsExt};
+use std::{fmt, fs, os::unix::fs::PermissionsExt, str::FromStr};
 
 use dusa_collection_utils::{
     errors::{ErrorArrayItem, Errors},
     types::PathType,
 };
 use nix::unistd::{chown, Gid, Uid};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use users::{Groups, Users, UsersCache};
 use walkdir::WalkDir;
 
@@ -13,7 +17,7 @@ pub fn get_id(user: &str) -> Result<(Uid, Gid), ErrorArrayItem> {
     let user_cache: UsersCache = UsersCache::new();
 
     let uid_result: Result<u32, ErrorArrayItem> =
-        match user_cache.get_user_by_name(&format! {"{}", user}) {
+        match user_cache.get_user_by_name(user) {
             Some(d) => Ok(d.uid()),
             None => Err(ErrorArrayItem::new(
                 Errors::GeneralError,
@@ -22,7 +26,7 @@ pub fn get_id(user: &str) -> Result<(Uid, Gid), ErrorArrayItem> {
         };
 
     let gid_result: Result<u32, ErrorArrayItem> =
-        match user_cache.get_group_by_name(&format! {"{}", user}) {
+        match user_cache.get_group_by_name(user) {
             Some(d) => Ok(d.gid()),
             None => Err(ErrorArrayItem::new(
                 Errors::GeneralError,
@@ -58,19 +62,19 @@ pub fn set_file_ownership(path: &PathType, uid: Uid, gid: Gid) -> Result<(), Err
 }
 
 pub fn set_file_permission(path: PathType, permission: u32) -> Result<(), ErrorArrayItem> {
+    let mode: FileMode = FileMode::new(permission)?;
+    set_file_mode(path, mode)
+}
+
+pub fn set_file_mode(path: PathType, mode: FileMode) -> Result<(), ErrorArrayItem> {
     // Changing the permissions the socket
     let path_metadata = match fs::metadata(path.clone()) {
         Ok(d) => d,
         Err(e) => return Err(ErrorArrayItem::from(e)),
     };
 
-    let permission_string: String = format!("0o{}", permission);
-    let permission_int: u32 = permission_string
-        .parse::<u32>()
-        .map_err(|e| ErrorArrayItem::from(e))?;
-
     let mut permissions = path_metadata.permissions();
-    permissions.set_mode(permission_int); // Set desired permissions
+    permissions.set_mode(mode.bits()); // Set desired permissions
 
     if let Err(err) = fs::set_permissions(path.clone(), permissions) {
         return Err(ErrorArrayItem::from(err));
@@ -78,3 +82,164 @@ pub fn set_file_permission(path: PathType, permission: u32) -> Result<(), ErrorA
 
     Ok(())
 }
+
+/// A unix file mode, always read and written in octal.
+///
+/// In config it has to be an octal string (`"0600"`, `"0o600"` or `"600"`).
+/// Integers are rejected: TOML can't tell `0o600` from `384`, and a bare
+/// `600` would quietly become `0o1130`. Modes above `0o7777` and strings with
+/// the digits 8 or 9 are rejected too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileMode(u32);
+
+impl FileMode {
+    pub const MAX: u32 = 0o7777;
+
+    pub fn new(bits: u32) -> Result<Self, ErrorArrayItem> {
+        if bits > Self::MAX {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidType,
+                format!("File mode {:#o} is out of range", bits),
+            ));
+        }
+        Ok(Self(bits))
+    }
+
+    /// Parses an octal mode string, with or without a `0o` or leading `0`
+    pub fn parse(mode: &str) -> Result<Self, ErrorArrayItem> {
+        let trimmed: &str = mode.trim();
+        let digits: &str = trimmed.strip_prefix("0o").unwrap_or(trimmed);
+
+        let bits: u32 = u32::from_str_radix(digits, 8).map_err(|_| {
+            ErrorArrayItem::new(
+                Errors::InvalidType,
+                format!("'{}' is not an octal file mode", mode),
+            )
+        })?;
+
+        Self::new(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
+impl fmt::Octal for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Octal::fmt(&self.0, f)
+    }
+}
+
+impl FromStr for FileMode {
+    type Err = ErrorArrayItem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Serialize for FileMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FileModeVisitor;
+
+        impl<'de> Visitor<'de> for FileModeVisitor {
+            type Value = FileMode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an octal file mode such as \"0600\"")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<FileMode, E> {
+                FileMode::parse(value).map_err(|err| E::custom(err.err_mesg))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<FileMode, E> {
+                Err(E::custom(format!(
+                    "File mode {} has to be an octal string such as \"0600\"",
+                    value
+                )))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<FileMode, E> {
+                Err(E::custom(format!(
+                    "File mode {} has to be an octal string such as \"0600\"",
+                    value
+                )))
+            }
+        }
+
+        deserializer.deserialize_any(FileModeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Wrapper {
+        mode: FileMode,
+    }
+
+    fn from_toml(value: &str) -> Result<FileMode, toml::de::Error> {
+        toml::from_str::<Wrapper>(&format!("mode = {}", value)).map(|w| w.mode)
+    }
+
+    #[test]
+    fn parses_octal_strings() {
+        assert_eq!(from_toml("\"0600\"").unwrap().bits(), 0o600);
+        assert_eq!(from_toml("\"0o640\"").unwrap().bits(), 0o640);
+        assert_eq!(from_toml("\"755\"").unwrap().bits(), 0o755);
+    }
+
+    #[test]
+    fn integers_are_rejected() {
+        assert!(from_toml("0o600").is_err());
+        assert!(from_toml("384").is_err());
+    }
+
+    #[test]
+    fn decimal_looking_mode_is_an_error() {
+        let err = from_toml("600").unwrap_err();
+        assert!(err.to_string().contains("octal string"));
+    }
+
+    #[test]
+    fn rejects_malformed_modes() {
+        assert!(from_toml("\"0800\"").is_err());
+        assert!(from_toml("\"rw-r--r--\"").is_err());
+        assert!(from_toml("\"\"").is_err());
+        assert!(from_toml("\"17777\"").is_err());
+        assert!(from_toml("-1").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        let mode: FileMode = FileMode::new(0o600).unwrap();
+        assert_eq!(mode.to_string(), "0600");
+        assert_eq!(format!("{:#o}", mode), "0o600");
+        assert_eq!(mode.to_string().parse::<FileMode>().unwrap(), mode);
+    }
+
+    #[test]
+    fn set_file_permission_takes_mode_bits() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path: PathType = PathType::PathBuf(file.path().to_path_buf());
+        set_file_permission(path.clone(), 0o640).unwrap();
+        let mode: u32 = fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o640);
+    }
+}